@@ -1,24 +1,239 @@
 use crate::crypto::*;
 use crate::spec::UID;
 use crypto_box::{PublicKey, SecretKey};
+use hmac::{Hmac, Mac};
 use log::debug;
-use num_bigint::BigInt;
+use num_bigint::{BigInt, RandBigInt};
 use num_primes::Generator;
 use pbkdf2::{
-    password_hash::{PasswordHash, PasswordHasher, SaltString},
+    password_hash::{PasswordHasher, Salt, SaltString},
     Pbkdf2,
 };
-use rand::distributions::{Distribution, Uniform};
 use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, Zeroizing};
 
 pub type Share = [BigInt; 2];
 pub type ShareValue = BigInt;
-pub type ShareIndex = u64;
+
+/*
+ * A secret field element (e.g., a key or a password point), wiped when dropped.
+ *
+ * num-bigint can't zeroize, so this overwrites the digits in place; that is best
+ * effort, as copies made by arithmetic on the value aren't wiped.
+ */
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretInt(pub BigInt);
+
+impl Deref for SecretInt {
+    type Target = BigInt;
+    fn deref(&self) -> &BigInt {
+        &self.0
+    }
+}
+
+impl Zeroize for SecretInt {
+    fn zeroize(&mut self) {
+        zeroize_bigint(&mut self.0);
+    }
+}
+
+impl Drop for SecretInt {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+// overwrites the value's digits with zeros, leaving it zero
+pub fn zeroize_bigint(value: &mut BigInt) {
+    let zeros = vec![0u32; value.iter_u32_digits().len()];
+    value.assign_from_slice(num_bigint::Sign::Plus, &zeros);
+}
+
+// identifies the prime a share was made under: the first 8 bytes of the
+// SHA-256 hash of the prime's big-endian bytes
+pub type PrimeId = [u8; 8];
+
+// domain separation tag for share locators; bump the version (and add a new
+// ShareIndex variant) if the construction ever changes
+pub const SHARE_LOCATOR_V1_TAG: &[u8] = b"hydra-share-locator-v1";
+
+// where load_locator_key looks for the locator key: the base64-encoded key
+// itself, or the path of a keyfile holding it
+pub const LOCATOR_KEY_ENV: &str = "HYDRA_LOCATOR_KEY";
+pub const LOCATOR_KEYFILE_ENV: &str = "HYDRA_LOCATOR_KEYFILE";
+pub const LOCATOR_KEY_LEN: usize = 32;
+
+// the locator key from the environment, so that it stays the same across
+// restarts; fails with NotFound if neither variable is set
+pub fn load_locator_key() -> io::Result<Zeroizing<Vec<u8>>> {
+    let key = load_key(LOCATOR_KEY_ENV, LOCATOR_KEYFILE_ENV)?;
+    if key.len() != LOCATOR_KEY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed locator key",
+        ));
+    }
+    Ok(key)
+}
+
+// a base64-encoded key from the first variable, or else from the keyfile the
+// second one names
+fn load_key(key_env: &str, keyfile_env: &str) -> io::Result<Zeroizing<Vec<u8>>> {
+    let encoded = match (std::env::var(key_env), std::env::var_os(keyfile_env)) {
+        (Ok(encoded), _) => Zeroizing::new(encoded),
+        (Err(_), Some(path)) => Zeroizing::new(std::fs::read_to_string(path)?),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("neither {} nor {} is set", key_env, keyfile_env),
+            ))
+        }
+    };
+    base64::decode(encoded.trim())
+        .map(Zeroizing::new)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed key"))
+}
+
+// derives (from the locator key) the key TOTP seeds are encrypted to
+pub const TOTP_SEED_KEY_TAG: &[u8] = b"hydra-totp-seed-key-v1";
+
+/*
+ * Where a user's server-held shares are stored.
+ *
+ * V1 locators are HMAC-SHA256(locator key, tag || len(uid) || uid || password),
+ * where the locator key is a server secret and len(uid) is 8 bytes little-endian.
+ * Legacy locators hashed uid || password with std's DefaultHasher, which is not
+ * stable across Rust releases; they are migrated to V1 on the user's next login.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ShareIndex {
+    Legacy(u64),
+    V1([u8; 32]),
+}
+
+pub const RECOVERY_SHARE_PREFIX: &str = "hydra-share:";
+pub const RECOVERY_SHARE_VERSION: u8 = 1;
+
+/*
+ * A user-held share, with what's needed to use it, in a form users can save.
+ *
+ * Encoded as RECOVERY_SHARE_PREFIX followed by URL-safe, unpadded base64 of
+ *   version (1 byte) || prime id (8 bytes) || index || x || y || checksum
+ * where the index is a tag byte (0 for Legacy, 1 for V1) and its bytes (a
+ * little-endian u64, or 32 bytes), x and y are each a big-endian u16 length and
+ * that many big-endian bytes, and the checksum is the first 4 bytes of the
+ * SHA-256 hash of everything before it.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecoveryShare {
+    pub share: Share,
+    pub index: ShareIndex,
+    pub prime_id: PrimeId,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecoveryShareError {
+    MissingPrefix,
+    // not valid base64
+    BadEncoding,
+    // likely a typo
+    BadChecksum,
+    UnsupportedVersion(u8),
+    Malformed,
+}
+
+impl RecoveryShare {
+    pub fn encode(&self) -> String {
+        let mut bytes = vec![RECOVERY_SHARE_VERSION];
+        bytes.extend_from_slice(&self.prime_id);
+        match self.index {
+            ShareIndex::Legacy(index) => {
+                bytes.push(0);
+                bytes.extend_from_slice(&index.to_le_bytes());
+            }
+            ShareIndex::V1(index) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&index);
+            }
+        }
+        for coordinate in self.share.iter() {
+            let (_, coordinate) = coordinate.to_bytes_be();
+            bytes.extend_from_slice(&(coordinate.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&coordinate);
+        }
+        let checksum = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&checksum[..4]);
+        format!(
+            "{}{}",
+            RECOVERY_SHARE_PREFIX,
+            base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    // whitespace (e.g., from line wrapping) is ignored
+    pub fn decode(code: &str) -> Result<RecoveryShare, RecoveryShareError> {
+        let code: String = code.split_whitespace().collect();
+        let code = code
+            .strip_prefix(RECOVERY_SHARE_PREFIX)
+            .ok_or(RecoveryShareError::MissingPrefix)?;
+        let bytes = base64::decode_config(code, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| RecoveryShareError::BadEncoding)?;
+        if bytes.len() < 5 {
+            return Err(RecoveryShareError::Malformed);
+        }
+        let (bytes, checksum) = bytes.split_at(bytes.len() - 4);
+        if Sha256::digest(bytes)[..4] != *checksum {
+            return Err(RecoveryShareError::BadChecksum);
+        }
+        if bytes[0] != RECOVERY_SHARE_VERSION {
+            return Err(RecoveryShareError::UnsupportedVersion(bytes[0]));
+        }
+
+        let mut rest = &bytes[1..];
+        let mut take = |n: usize| {
+            if rest.len() < n {
+                return Err(RecoveryShareError::Malformed);
+            }
+            let (taken, remaining) = rest.split_at(n);
+            rest = remaining;
+            Ok(taken)
+        };
+        let prime_id = take(8)?.try_into().unwrap();
+        let index = match take(1)?[0] {
+            0 => ShareIndex::Legacy(u64::from_le_bytes(take(8)?.try_into().unwrap())),
+            1 => ShareIndex::V1(take(32)?.try_into().unwrap()),
+            _ => return Err(RecoveryShareError::Malformed),
+        };
+        let mut coordinate = || -> Result<BigInt, RecoveryShareError> {
+            let len = u16::from_be_bytes(take(2)?.try_into().unwrap());
+            Ok(BigInt::from_bytes_be(
+                num_bigint::Sign::Plus,
+                take(len as usize)?,
+            ))
+        };
+        let share = [coordinate()?, coordinate()?];
+        if !rest.is_empty() {
+            return Err(RecoveryShareError::Malformed);
+        }
+        Ok(RecoveryShare {
+            share,
+            index,
+            prime_id,
+        })
+    }
+}
 
 #[derive(Clone)]
 pub struct UserCreds {
@@ -29,457 +244,3613 @@ pub struct UserCreds {
 
 pub struct Authorizer {
     user_creds: HashMap<UID, UserCreds>,
-    shares_map: HashMap<ShareIndex, ShareStore>,
-    prime: BigInt,
+    // sealed under the master key
+    shares_map: HashMap<ShareIndex, SealedShareStore>,
+    master_key: MasterKey,
+    // legacy locators (e.g., in old recovery shares) of migrated share stores
+    legacy_indices: HashMap<ShareIndex, ShareIndex>,
+    locator_key: Zeroizing<Vec<u8>>,
+    // every prime that stored shares may have been made under
+    primes: HashMap<PrimeId, BigInt>,
+    // the group share commitments are computed in, for each prime
+    groups: HashMap<PrimeId, CommitmentGroup>,
+    // the prime new shares are made under
+    prime_id: PrimeId,
+    // the KDF new password shares are made with
+    password_kdf: PasswordKdf,
+    failed_unlocks: HashMap<UID, FailedUnlocks>,
+    lockout_policy: LockoutPolicy,
+    clock: Box<dyn Clock>,
+    // reconstructed keys of unlocked users
+    sessions: HashMap<SessionToken, Session>,
+    session_ttl: Duration,
+    // shares of users' keys held by their trusted contacts
+    social_recoveries: HashMap<UID, SocialRecovery>,
+    // users whose server shares are only released with a TOTP code
+    totp: HashMap<UID, TotpEnrollment>,
+    // seeds from enroll_totp that haven't been confirmed with a code yet
+    pending_totp: HashMap<UID, EncData>,
+    // how many time steps a code may be off by
+    totp_skew: u64,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct ShareStore {
-    pub share: Share,
-    pub share_value: ShareValue,
-    pub password_salt: String,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    UnknownUser,
+    // no shares are stored for this user and password
+    WrongPassword,
+    // too many recent failed unlocks
+    Locked { retry_after: Duration },
+    NotEnoughShares,
+    // the shares are malformed, unknown, or don't reconstruct the user's key
+    InvalidShares,
+    // the shares were made under a prime that hasn't been registered
+    UnknownPrime,
+    // the session doesn't exist, has expired, or was locked
+    InvalidSession,
+    // a share doesn't match the commitments stored with the user's shares
+    TamperedShare(ShareSource),
+    // a share from before the user's shares were last refreshed or re-keyed
+    StaleShare(ShareSource),
+    // more shares are required than there are holders, or fewer than 2
+    InvalidThreshold,
+    // a trusted contact is the user themselves, or is listed more than once
+    InvalidContact(UID),
+    // the server's shares are needed, and the user has enrolled in TOTP
+    TotpRequired,
+    // the TOTP code is wrong, expired, or was already used
+    WrongCode,
+    // a share store can't be opened with the master key: it was sealed under
+    // another one, or is corrupt
+    WrongMasterKey,
 }
 
-pub fn generate_keypair() -> (SecretKey, PublicKey) {
-    let mut rng = rand::thread_rng();
-    let secretkey = SecretKey::generate(&mut rng);
-    let pubkey = PublicKey::from(&secretkey);
-    debug!(
-        "secretkey {} and pubkey {}",
-        base64::encode(secretkey.as_bytes()),
-        base64::encode(pubkey.as_bytes()),
-    );
-    let pk_hash = {
-        let mut hasher = DefaultHasher::new();
-        (base64::encode(secretkey.as_bytes())).hash(&mut hasher);
-        hasher.finish()
-    };
-    (secretkey, pubkey)
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
-impl Authorizer {
-    pub fn new() -> Authorizer {
-        let prime_arr: [u8; 64];
-        loop {
-            let prime_gen = Generator::new_prime(512).to_bytes_le();
-            let try_pa: std::result::Result<[u8; 64], _> = prime_gen.try_into();
-            match try_pa {
-                Ok(p) => {
-                    prime_arr = p;
-                    break;
-                }
-                Err(_) => continue,
-            }
-        }
+impl std::error::Error for AuthError {}
+
+// names a share that failed verification
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShareSource {
+    // the server's share at the password point
+    Password,
+    // by position in the server's share store
+    Server(usize),
+    // by position in the user shares passed in
+    User(usize),
+}
 
-        Authorizer {
-            user_creds: HashMap::new(),
-            shares_map: HashMap::new(),
-            prime: BigInt::from_bytes_le(num_bigint::Sign::Plus, &prime_arr),
+/*
+ * How failed unlocks are throttled
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    // delay after the first failure, doubled after each consecutive failure
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // after this many consecutive failures, wait lockout_duration instead
+    pub max_failures: u32,
+    pub lockout_duration: Duration,
+}
+
+impl LockoutPolicy {
+    // how long to wait after the nth consecutive failure
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures >= self.max_failures {
+            return self.lockout_duration;
         }
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        std::cmp::min(self.base_delay.saturating_mul(factor), self.max_delay)
     }
+}
 
-    pub fn register_user_shares(&mut self, uid: &UID, password: &str) -> (Share, ShareIndex) {
-        let (secretkey, _pubkey) = generate_keypair();
+impl Default for LockoutPolicy {
+    fn default() -> LockoutPolicy {
+        LockoutPolicy {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            max_failures: 10,
+            lockout_duration: Duration::from_secs(60 * 60),
+        }
+    }
+}
 
-        let salt = SaltString::generate(&mut OsRng);
-        let pass_info: String = Pbkdf2
-            .hash_password(password.as_bytes(), &salt)
-            .unwrap()
-            .to_string();
-        let _parsed_hash = PasswordHash::new(&pass_info).unwrap();
-        let hash_pass_bigint = BigInt::from_bytes_le(num_bigint::Sign::Plus, pass_info.as_bytes());
-        let secretkey_int = BigInt::from_bytes_le(num_bigint::Sign::Plus, secretkey.as_bytes());
+/*
+ * A key rotation in progress: the user's old key, and the new keypair that
+ * will replace it once anything encrypted to the old key has been re-encrypted
+ */
+pub struct KeyRotation {
+    uid: UID,
+    index: ShareIndex,
+    password: Zeroizing<String>,
+    pub old_key: Zeroizing<Vec<u8>>,
+    pub new_key: SecretKey,
+    pub new_pubkey: PublicKey,
+}
 
-        let sss = ShamirSecretSharing {
-            threshold: 1,
-            share_count: 3,
-            prime: self.prime.clone(),
-        };
-        // returned format: vec < [h(p), f(h(p))], [rand1, f(rand1)], [rand2, f(rand2)] >
-        let all_shares = sss.share(&secretkey_int, &hash_pass_bigint);
-
-        let mut uid_owned = uid.clone().to_owned();
-        uid_owned.push_str(&password);
-        let uid_pw_hash = {
-            let mut hasher = DefaultHasher::new();
-            uid_owned.hash(&mut hasher);
-            hasher.finish()
-        };
-        debug!(
-            "got uid, password {} {}: hash {}",
-            uid, password, uid_pw_hash
-        );
+/*
+ * A sharing of a user's key among their trusted contacts (separate from the
+ * user's share store), each share encrypted to its contact's public key
+ */
+#[derive(Clone)]
+struct SocialRecovery {
+    required: usize,
+    prime_id: PrimeId,
+    // the user's share store, replaced when the user recovers their account
+    index: ShareIndex,
+    shares: Vec<(UID, EncData)>,
+}
 
-        // save share
-        let perm_share = ShareStore {
-            share: all_shares[1].clone(),
-            share_value: all_shares[0][1].clone(),
-            password_salt: salt.clone().as_str().to_string(),
-        };
-        // persist share info at share_loc
-        self.shares_map.insert(uid_pw_hash, perm_share.clone());
-        debug!("user share: {:?}", perm_share.share);
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(15 * 60);
 
-        (all_shares[2].clone(), uid_pw_hash)
-    }
+/*
+ * Lets operations use a user's key without the password, until the session
+ * expires or is locked
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionToken([u8; 32]);
 
-    pub fn get_priv_key(
-        &self,
-        uid: &UID,
-        password: Option<String>,
-        share: Option<(Share, ShareIndex)>,
-    ) -> Option<Vec<u8>> {
-        let mut shares: Vec<[BigInt; 2]> = vec![];
+struct Session {
+    uid: UID,
+    priv_key: Zeroizing<Vec<u8>>,
+    expires_at: SystemTime,
+}
 
-        if share != None {
-            shares.push(share.clone().unwrap().0);
+struct FailedUnlocks {
+    count: u32,
+    retry_at: SystemTime,
+}
 
-            if let Some(share) = self.shares_map.get(&share.clone().unwrap().1) {
-                debug!("getting users share");
-                shares.push(share.share.clone());
-            }
-        } else {
-            debug!("using uid and pw");
+/*
+ * TOTP (RFC 6238) codes: HMAC-SHA1, 6 digits, 30 second steps, as authenticator
+ * apps expect. The seed is stored encrypted, and the last step a code was
+ * accepted for is remembered so codes can't be replayed.
+ */
+struct TotpEnrollment {
+    seed: EncData,
+    last_step: Option<u64>,
+}
 
-            if password == None {
-                debug!("no password?");
-                return None;
-            }
-            let password_str = password.unwrap();
-            let mut uid_owned = uid.clone().to_owned();
+pub const TOTP_STEP: Duration = Duration::from_secs(30);
+pub const TOTP_DIGITS: u32 = 6;
+pub const TOTP_SEED_LEN: usize = 20;
+pub const DEFAULT_TOTP_SKEW: u64 = 1;
+
+// the time step a code is for
+pub fn totp_step(time: SystemTime) -> u64 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    secs / TOTP_STEP.as_secs()
+}
 
-            uid_owned.push_str(&password_str);
-            let uid_pw_hash = {
-                let mut hasher = DefaultHasher::new();
-                uid_owned.hash(&mut hasher);
-                hasher.finish()
-            };
-            debug!(
-                "got uid, password {} {}: hash {}",
-                uid, password_str, uid_pw_hash
-            );
+pub fn totp_code(seed: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(seed).expect("HMAC can take a key of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    code % 10u32.pow(TOTP_DIGITS)
+}
 
-            if let Some(share) = self.shares_map.get(&uid_pw_hash) {
-                debug!("getting users share");
-                shares.push(share.share.clone());
+// lets tests control time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
 
-                let pass_info: String = Pbkdf2
-                    .hash_password(password_str.as_bytes(), &share.password_salt)
-                    .unwrap()
-                    .to_string();
-                let hash_pass_bigint =
-                    BigInt::from_bytes_le(num_bigint::Sign::Plus, pass_info.as_bytes());
+pub struct SystemClock;
 
-                debug!("hash_pass_bigint: {}", hash_pass_bigint);
-                debug!("user share: {:?}", share.share);
-                let other_share = [hash_pass_bigint, share.share_value.clone()];
-                shares.push(other_share);
-            }
-        }
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
 
-        if shares.len() != 2 {
-            debug!("Unable to reconstruct due to too few shares");
-            return None;
-        }
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareStore {
+    // shares held by the server
+    pub shares: Vec<Share>,
+    // f(h(password)), the value of the share at the password point
+    pub share_value: ShareValue,
+    pub password_salt: String,
+    // how the password point was derived from the password and salt
+    pub password_kdf: PasswordKdf,
+    // how the key was split, so it can be re-shared the same way
+    pub config: ShareConfig,
+    // the prime defining the field the shares were computed in
+    pub prime_id: PrimeId,
+    // Feldman commitments to the coefficients of the sharing polynomial; share
+    // stores made before these were recorded have none, and aren't verified
+    #[serde(default)]
+    pub commitments: Vec<BigInt>,
+    // the commitments from before each refresh, to recognize stale shares
+    #[serde(default)]
+    pub retired_commitments: Vec<Vec<BigInt>>,
+}
 
-        let sss = ShamirSecretSharing {
-            threshold: 1,
-            share_count: 3,
-            prime: self.prime.clone(),
-        };
-        let priv_key = sss.reconstruct(&shares);
-        let pkbytes = get_pk_bytes(priv_key.to_bytes_le().1);
-        return Some(pkbytes.to_vec());
+impl Drop for ShareStore {
+    fn drop(&mut self) {
+        self.shares.iter_mut().flatten().for_each(zeroize_bigint);
+        zeroize_bigint(&mut self.share_value);
     }
 }
 
+// identifies the master key a share store was sealed under: the first 8 bytes of
+// the SHA-256 hash of the master key's public key
+pub type MasterKeyId = [u8; 8];
+
+// where MasterKey::load looks for the master key, as with the locator key;
+// MasterKey::from_env only reads the first
+pub const MASTER_KEY_ENV: &str = "HYDRA_MASTER_KEY";
+pub const MASTER_KEYFILE_ENV: &str = "HYDRA_MASTER_KEYFILE";
+
 /*
- * SHAMIR SECRET SHARING STUFF
+ * A share store as it's kept at rest: serialized and encrypted to the master key,
+ * so that a dump of the stores is useless without it (even with a user's password)
  */
-pub struct ShamirSecretSharing {
-    /// Maximum number of shares that can be known without exposing the secret.
-    pub threshold: usize,
-    /// Number of shares to split the secret into.
-    pub share_count: usize,
-    /// Prime defining the Zp field in which computation is taking place.
-    pub prime: BigInt,
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedShareStore {
+    pub master_key_id: MasterKeyId,
+    pub store: EncData,
 }
 
-impl ShamirSecretSharing {
-    /// Minimum number of shares required to reconstruct secret.
-    ///
-    /// For this scheme this is always `threshold + 1`.
-    pub fn reconstruct_limit(&self) -> usize {
-        self.threshold + 1
+/*
+ * The server secret share stores are sealed under. It must be kept apart from
+ * the stores (e.g., in a keyfile or the environment), where it's base64-encoded.
+ */
+#[derive(Clone)]
+pub struct MasterKey(SecretKey);
+
+impl MasterKey {
+    pub fn generate() -> MasterKey {
+        MasterKey(SecretKey::generate(&mut OsRng))
     }
 
-    /// Generate `share_count` shares from `secret`.
-    pub fn share(&self, secret: &BigInt, hash_pass: &BigInt) -> Vec<[BigInt; 2]> {
-        let result = self.sample_polynomial(secret, hash_pass);
-        self.evaluate_polynomial(&result[0], &result[1])
+    // master keys are exactly 32 bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<MasterKey> {
+        let key = Zeroizing::new(get_pk_bytes(bytes)?);
+        Some(MasterKey(SecretKey::from(*key)))
     }
 
-    /// Reconstruct `secret` from a large enough subset of the shares.
-    ///
-    /// `indices` are the ranks of the known shares as output by the `share` method,
-    /// while `values` are the actual values of these shares.
-    /// Both must have the same number of elements, and at least `reconstruct_limit`.
-    pub fn reconstruct(&self, shares: &Vec<[BigInt; 2]>) -> BigInt {
-        assert!(shares.len() >= self.reconstruct_limit());
-        // add one to indices to get points
-        let x_values: Vec<BigInt> = (0..self.reconstruct_limit())
-            .map(|i| shares[i][0].clone())
-            .collect();
-        let y_values: Vec<BigInt> = (0..self.reconstruct_limit())
-            .map(|i| shares[i][1].clone())
-            .collect();
-        lagrange_interpolation_at_zero(&x_values, &y_values, &self.prime)
+    pub fn from_base64(encoded: &str) -> Option<MasterKey> {
+        let bytes = Zeroizing::new(base64::decode(encoded.trim()).ok()?);
+        MasterKey::from_bytes(&bytes)
     }
 
-    fn sample_polynomial(&self, zero_value: &BigInt, hash_pass: &BigInt) -> [Vec<BigInt>; 2] {
-        // fix the first coefficient (corresponding to the evaluation at zero)
-        let mut coefficients = vec![zero_value.clone()];
-        // sample the remaining coefficients randomly using secure randomness
-        let range = Uniform::from(0..i64::MAX);
-        let mut rng = rand::thread_rng();
-        let random_coefficients: Vec<BigInt> = (0..self.threshold)
-            .map(|_| BigInt::from(range.sample(&mut rng)))
-            .collect();
-        coefficients.extend(random_coefficients);
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(base64::encode(self.0.as_bytes()))
+    }
 
-        let mut points = vec![hash_pass.clone()];
-        let random_points: Vec<BigInt> = (0..self.share_count - 1)
-            .map(|_| BigInt::from(range.sample(&mut rng)))
-            .collect();
-        points.extend(random_points);
-        // return
-        [coefficients, points]
+    pub fn from_keyfile(path: &Path) -> io::Result<MasterKey> {
+        let encoded = Zeroizing::new(std::fs::read_to_string(path)?);
+        MasterKey::from_base64(&encoded)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed master key"))
     }
 
-    fn evaluate_polynomial(&self, coefficients: &[BigInt], points: &[BigInt]) -> Vec<[BigInt; 2]> {
-        // evaluate at all points
-        let mut result: Vec<[BigInt; 2]> = vec![];
-        for point in points {
-            let y_val = mod_evaluate_polynomial(coefficients, point, &self.prime);
-            result.push([point.clone(), y_val.clone()]);
-        }
-        result
+    // fails with NotFound if MASTER_KEY_ENV isn't set
+    pub fn from_env() -> io::Result<MasterKey> {
+        let encoded = std::env::var(MASTER_KEY_ENV)
+            .map(Zeroizing::new)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "master key not set"))?;
+        MasterKey::from_base64(&encoded)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed master key"))
     }
-}
 
-pub fn mod_evaluate_polynomial(coefficients: &[BigInt], point: &BigInt, prime: &BigInt) -> BigInt {
-    // evaluate using Horner's rule
-    //  - to combine with fold we consider the coefficients in reverse order
-    let mut reversed_coefficients = coefficients.iter().rev();
-    // manually split due to fold insisting on an initial value
-    let head = reversed_coefficients.next().unwrap();
-    let tail = reversed_coefficients;
-    tail.fold(head.clone(), |partial, coef| {
-        (partial * point + coef) % prime
-    })
-}
+    // the master key from MASTER_KEY_ENV, or else the keyfile MASTER_KEYFILE_ENV
+    // names; fails with NotFound if neither is set
+    pub fn load() -> io::Result<MasterKey> {
+        let key = load_key(MASTER_KEY_ENV, MASTER_KEYFILE_ENV)?;
+        MasterKey::from_bytes(&key)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed master key"))
+    }
 
-pub fn gcd(a: &BigInt, b: &BigInt) -> (BigInt, BigInt, BigInt) {
-    if *b == BigInt::from(0) {
-        (a.clone(), BigInt::from(1), BigInt::from(0))
-    } else {
-        let n = a.checked_div(b).expect("dividing overflowed");
-        let c = a % b;
-        let r = gcd(b, &c);
-        (r.0, r.2.clone(), r.1 - r.2.clone() * n)
+    pub fn id(&self) -> MasterKeyId {
+        let hash = Sha256::digest(self.0.public_key().as_bytes());
+        hash[..8].try_into().unwrap()
     }
-}
 
-/// Inverse of `k` in the *Zp* field defined by `prime`.
-pub fn mod_inverse(k: &BigInt, prime: &BigInt) -> BigInt {
-    let k2 = k % prime;
-    let r = if k2 < BigInt::from(0) {
-        -gcd(prime, &-k2).2
-    } else {
-        gcd(prime, &k2).2
-    };
-    (prime + r) % prime
-}
+    fn seal(&self, store: &ShareStore) -> SealedShareStore {
+        let bytes = Zeroizing::new(bincode::serialize(store).unwrap());
+        SealedShareStore {
+            master_key_id: self.id(),
+            store: encrypt_with_pubkey(&self.0.public_key(), &bytes),
+        }
+    }
 
-pub fn lagrange_interpolation_at_zero(
-    points: &[BigInt],
-    values: &[BigInt],
-    prime: &BigInt,
-) -> BigInt {
-    assert_eq!(points.len(), values.len());
-    // Lagrange interpolation for point 0
-    let mut acc = BigInt::from(0);
-    for i in 0..values.len() {
-        let xi = &points[i];
-        let yi = &values[i];
-        let mut num = BigInt::from(1);
-        let mut denum = BigInt::from(1);
-        for j in 0..values.len() {
-            if j != i {
-                let xj = &points[j];
-                let num_xj_prod = num.checked_mul(xj).expect("multiplication overflow");
-                num = modulus(&num_xj_prod, &prime);
-                let xj_xi_diff = xj.checked_sub(xi).expect("subtraction overflow");
-                let dnum_diff_prod = denum
-                    .checked_mul(&xj_xi_diff)
-                    .expect("second multiplication overflow");
-                denum = modulus(&dnum_diff_prod, prime);
+    fn open(&self, sealed: &SealedShareStore) -> Result<ShareStore, AuthError> {
+        if sealed.master_key_id != self.id() {
+            debug!(
+                "share store sealed under master key {:?}",
+                sealed.master_key_id
+            );
+            return Err(AuthError::WrongMasterKey);
+        }
+        let key = Zeroizing::new(self.0.as_bytes().to_vec());
+        match decrypt_encdata(&sealed.store, &key) {
+            (true, bytes) => {
+                let bytes = Zeroizing::new(bytes);
+                bincode::deserialize(&bytes).map_err(|_| AuthError::WrongMasterKey)
             }
+            _ => Err(AuthError::WrongMasterKey),
         }
-        let mod_inv = mod_inverse(&denum, &prime);
-        let prod1 = yi.checked_mul(&num).expect("multiplication overflow");
-        let prod2 = prod1
-            .checked_mul(&mod_inv)
-            .expect("multiplication overflow");
-        let sum = acc.checked_add(&prod2).expect("addition overflow");
-        acc = sum % prime;
     }
-    acc
 }
 
-fn modulus(a: &BigInt, m: &BigInt) -> BigInt {
-    ((a % m) + m) % m
+/*
+ * Who holds each share of a user's key
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShareRole {
+    // the share at the point derived from the user's password
+    Password,
+    // persisted by the server
+    Server,
+    // returned to the user to save
+    User,
+    BackupCode,
+    TrustedContact,
+}
+
+/*
+ * How to split a user's key: any `required` of the shares in `roles`
+ * reconstruct the key
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareConfig {
+    required: usize,
+    roles: Vec<ShareRole>,
+}
+
+impl ShareConfig {
+    // the config must have exactly one password share, and the server must not
+    // hold enough shares to reconstruct the key by itself
+    pub fn new(required: usize, roles: Vec<ShareRole>) -> Option<ShareConfig> {
+        let npassword = roles.iter().filter(|r| **r == ShareRole::Password).count();
+        let nserver = roles.iter().filter(|r| **r == ShareRole::Server).count();
+        if npassword != 1 || required < 2 || required > roles.len() || nserver >= required {
+            return None;
+        }
+        Some(ShareConfig { required, roles })
+    }
+
+    pub fn required(&self) -> usize {
+        self.required
+    }
+
+    pub fn roles(&self) -> &[ShareRole] {
+        &self.roles
+    }
+}
+
+impl Default for ShareConfig {
+    // 2-of-3: the password, the server's share, and the user's share
+    fn default() -> ShareConfig {
+        ShareConfig {
+            required: 2,
+            roles: vec![ShareRole::Password, ShareRole::Server, ShareRole::User],
+        }
+    }
+}
+
+/*
+ * How a password is stretched into the x-coordinate of the password share
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PasswordKdf {
+    // PBKDF2-SHA256; the point is the PHC string. Stores from before the
+    // parameters were recorded used the values of PBKDF2_ROUNDS/PBKDF2_OUTPUT_LEN.
+    Pbkdf2 {
+        #[serde(default = "default_pbkdf2_rounds")]
+        rounds: u32,
+        #[serde(default = "default_pbkdf2_output_len")]
+        output_len: usize,
+    },
+    // m_cost is in KiB
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+}
+
+// bytes of Argon2id/scrypt output used as the password point
+const KDF_OUTPUT_LEN: usize = 64;
+
+// the pbkdf2 crate's defaults when they were first used, pinned so that a
+// change to them doesn't orphan existing password shares
+pub const PBKDF2_ROUNDS: u32 = 10_000;
+pub const PBKDF2_OUTPUT_LEN: usize = 32;
+
+fn default_pbkdf2_rounds() -> u32 {
+    PBKDF2_ROUNDS
+}
+
+fn default_pbkdf2_output_len() -> usize {
+    PBKDF2_OUTPUT_LEN
+}
+
+impl Default for PasswordKdf {
+    fn default() -> PasswordKdf {
+        PasswordKdf::Pbkdf2 {
+            rounds: PBKDF2_ROUNDS,
+            output_len: PBKDF2_OUTPUT_LEN,
+        }
+    }
+}
+
+impl PasswordKdf {
+    // Argon2id with the argon2 crate's default costs
+    pub fn argon2id() -> PasswordKdf {
+        PasswordKdf::Argon2id {
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            p_cost: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+
+    // returns None if the parameters are invalid for the algorithm
+    pub fn derive(&self, password: &str, salt: &str) -> Option<SecretInt> {
+        let bytes = match self {
+            PasswordKdf::Pbkdf2 { rounds, output_len } => {
+                if *rounds == 0 {
+                    return None;
+                }
+                let params = pbkdf2::Params {
+                    rounds: *rounds,
+                    output_length: *output_len,
+                };
+                let salt = Salt::new(salt).ok()?;
+                Zeroizing::new(
+                    Pbkdf2
+                        .hash_password_customized(password.as_bytes(), None, None, params, salt)
+                        .ok()?
+                        .to_string()
+                        .into_bytes(),
+                )
+            }
+            PasswordKdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                let params =
+                    argon2::Params::new(*m_cost, *t_cost, *p_cost, Some(KDF_OUTPUT_LEN)).ok()?;
+                let argon2 = argon2::Argon2::new(
+                    argon2::Algorithm::Argon2id,
+                    argon2::Version::V0x13,
+                    params,
+                );
+                let mut out = Zeroizing::new(vec![0u8; KDF_OUTPUT_LEN]);
+                argon2
+                    .hash_password_into(password.as_bytes(), salt.as_bytes(), &mut out)
+                    .ok()?;
+                out
+            }
+            PasswordKdf::Scrypt { log_n, r, p } => {
+                let params = scrypt::Params::new(*log_n, *r, *p).ok()?;
+                let mut out = Zeroizing::new(vec![0u8; KDF_OUTPUT_LEN]);
+                scrypt::scrypt(password.as_bytes(), salt.as_bytes(), &params, &mut out).ok()?;
+                out
+            }
+        };
+        Some(SecretInt(BigInt::from_bytes_le(
+            num_bigint::Sign::Plus,
+            &bytes,
+        )))
+    }
+}
+
+pub fn generate_keypair() -> (SecretKey, PublicKey) {
+    let mut rng = rand::thread_rng();
+    let secretkey = SecretKey::generate(&mut rng);
+    let pubkey = PublicKey::from(&secretkey);
+    debug!("generated pubkey {}", base64::encode(pubkey.as_bytes()));
+    (secretkey, pubkey)
+}
+
+impl Authorizer {
+    // uses a fresh random locator key: shares won't be locatable by another Authorizer
+    pub fn new() -> Authorizer {
+        let mut locator_key = Zeroizing::new(vec![0u8; LOCATOR_KEY_LEN]);
+        OsRng.fill_bytes(&mut locator_key);
+        Authorizer::new_with_locator_key(&locator_key)
+    }
+
+    // the locator key must be kept secret and stable for the stored shares to be
+    // found. The master key is random, so stores sealed under it can't be opened
+    // after a restart; use new_with_keys for a persistent server.
+    pub fn new_with_locator_key(locator_key: &[u8]) -> Authorizer {
+        Authorizer::new_with_prime(locator_key, well_known_prime())
+    }
+
+    // both keys must be kept secret and stable (see load_locator_key and
+    // MasterKey::load) for the stored shares to be found and opened
+    pub fn new_with_keys(locator_key: &[u8], master_key: MasterKey) -> Authorizer {
+        Authorizer {
+            master_key,
+            ..Authorizer::new_with_locator_key(locator_key)
+        }
+    }
+
+    // new shares are made under `prime`; it must be persisted (or be well-known)
+    // for them to be reconstructed later
+    pub fn new_with_prime(locator_key: &[u8], prime: BigInt) -> Authorizer {
+        let prime_id = prime_id(&prime);
+        let mut groups = HashMap::new();
+        groups.insert(prime_id, CommitmentGroup::for_prime(&prime));
+        let mut primes = HashMap::new();
+        primes.insert(prime_id, prime);
+        Authorizer {
+            user_creds: HashMap::new(),
+            shares_map: HashMap::new(),
+            master_key: MasterKey::generate(),
+            legacy_indices: HashMap::new(),
+            locator_key: Zeroizing::new(locator_key.to_vec()),
+            primes,
+            groups,
+            prime_id,
+            password_kdf: PasswordKdf::default(),
+            failed_unlocks: HashMap::new(),
+            lockout_policy: LockoutPolicy::default(),
+            clock: Box::new(SystemClock),
+            sessions: HashMap::new(),
+            session_ttl: DEFAULT_SESSION_TTL,
+            social_recoveries: HashMap::new(),
+            totp: HashMap::new(),
+            pending_totp: HashMap::new(),
+            totp_skew: DEFAULT_TOTP_SKEW,
+        }
+    }
+
+    // re-seals every share store under the new master key, so that the old one
+    // (and dumps of the stores sealed under it) can be discarded. Nothing changes
+    // if any store can't be opened.
+    pub fn rotate_master_key(&mut self, master_key: MasterKey) -> Result<(), AuthError> {
+        let mut resealed = HashMap::new();
+        for (index, sealed) in &self.shares_map {
+            let store = self.master_key.open(sealed)?;
+            resealed.insert(*index, master_key.seal(&store));
+        }
+        debug!("rotating master key to {:?}", master_key.id());
+        self.shares_map = resealed;
+        self.master_key = master_key;
+        Ok(())
+    }
+
+    pub fn master_key_id(&self) -> MasterKeyId {
+        self.master_key.id()
+    }
+
+    // makes shares stored under a previously used prime reconstructable
+    pub fn register_prime(&mut self, prime: BigInt) -> PrimeId {
+        let id = prime_id(&prime);
+        self.groups
+            .entry(id)
+            .or_insert_with(|| CommitmentGroup::for_prime(&prime));
+        self.primes.insert(id, prime);
+        id
+    }
+
+    // new password shares use this KDF, and existing ones are upgraded to it
+    // the next time the user logs in with their password. Returns false (and
+    // keeps the current KDF) if the KDF's parameters are invalid.
+    pub fn set_password_kdf(&mut self, kdf: PasswordKdf) -> bool {
+        let salt = SaltString::generate(&mut OsRng);
+        if kdf.derive("", salt.as_str()).is_none() {
+            return false;
+        }
+        self.password_kdf = kdf;
+        true
+    }
+
+    // the prime new shares are made under
+    pub fn prime(&self) -> &BigInt {
+        &self.primes[&self.prime_id]
+    }
+
+    pub fn register_user_shares(&mut self, uid: &UID, password: &str) -> (Share, ShareIndex) {
+        let (shares, uid_pw_hash) =
+            self.register_user_shares_with_config(uid, password, &ShareConfig::default());
+        (shares[0].1.clone(), uid_pw_hash)
+    }
+
+    // returns the shares that aren't held by the server, in the order of their
+    // roles in the config, along with the index of the server's share store
+    pub fn register_user_shares_with_config(
+        &mut self,
+        uid: &UID,
+        password: &str,
+        config: &ShareConfig,
+    ) -> (Vec<(ShareRole, Share)>, ShareIndex) {
+        let (secretkey, pubkey) = generate_keypair();
+        let secretkey_int = SecretInt(key_to_bigint(secretkey.as_bytes()));
+        self.user_creds.insert(
+            uid.clone(),
+            UserCreds {
+                pubkey: Some(pubkey),
+                is_anon: false,
+                enc_locators_index: 0,
+            },
+        );
+        self.share_secret(uid, password, &secretkey_int, config)
+    }
+
+    // lets data be encrypted to a user without their password
+    pub fn get_pubkey(&self, uid: &UID) -> Option<PublicKey> {
+        self.user_creds.get(uid)?.pubkey.clone()
+    }
+
+    pub fn get_user_creds(&self, uid: &UID) -> Option<&UserCreds> {
+        self.user_creds.get(uid)
+    }
+
+    // a user-held share in a form users can save (see RecoveryShare::encode)
+    pub fn recovery_share(&self, share: &Share, index: &ShareIndex) -> Option<RecoveryShare> {
+        let store = self.open_store(&self.resolve_share_index(index)).ok()??;
+        Some(RecoveryShare {
+            share: share.clone(),
+            index: *index,
+            prime_id: store.prime_id,
+        })
+    }
+
+    // checks that a decoded recovery share belongs to a stored share store and
+    // lies in its field, returning the share and index to unlock with
+    pub fn check_recovery_share(
+        &self,
+        recovery: &RecoveryShare,
+    ) -> Result<(Share, ShareIndex), AuthError> {
+        let prime = self
+            .primes
+            .get(&recovery.prime_id)
+            .ok_or(AuthError::UnknownPrime)?;
+        let store = self
+            .open_store(&self.resolve_share_index(&recovery.index))?
+            .ok_or(AuthError::InvalidShares)?;
+        let [x, y] = &recovery.share;
+        if store.prime_id != recovery.prime_id || *x == BigInt::from(0) || x >= prime || y >= prime
+        {
+            return Err(AuthError::InvalidShares);
+        }
+        Ok((recovery.share.clone(), recovery.index))
+    }
+
+    // re-shares the user's existing key under the new password, so that data
+    // encrypted to the user stays readable. All previously issued shares
+    // (including those held by the user) stop working; the new ones are returned.
+    //
+    // `user_shares` are only needed if the user's config requires more shares
+    // than the password and the server's shares, and `totp_code` if the user has
+    // enrolled in TOTP (as for every method that unlocks the user's key).
+    pub fn change_password(
+        &mut self,
+        uid: &UID,
+        old_password: &str,
+        new_password: &str,
+        user_shares: Vec<(Share, ShareIndex)>,
+        totp_code: Option<u32>,
+    ) -> Result<(Vec<(ShareRole, Share)>, ShareIndex), AuthError> {
+        let old_index = self.migrate_share_index(uid, old_password);
+        self.reshare(
+            uid,
+            old_index,
+            Some(old_password),
+            user_shares,
+            new_password,
+            totp_code,
+        )
+    }
+
+    // "forgot password" flow: reconstructs the key from the shares the user saved
+    // at registration (e.g., their recovery share), and re-shares it under the new
+    // password. The shares used for recovery stop working, the user's sessions
+    // are locked, and fresh shares (including a new recovery share) are returned.
+    pub fn recover_account(
+        &mut self,
+        uid: &UID,
+        user_shares: Vec<(Share, ShareIndex)>,
+        new_password: &str,
+        totp_code: Option<u32>,
+    ) -> Result<(Vec<(ShareRole, Share)>, ShareIndex), AuthError> {
+        let old_index = match user_shares.first() {
+            Some((_, index)) => self.resolve_share_index(index),
+            None => return Err(AuthError::NotEnoughShares),
+        };
+        self.reshare(uid, old_index, None, user_shares, new_password, totp_code)
+    }
+
+    // unlocks the user's key and generates the one to replace it, without changing
+    // any state; see finish_key_rotation
+    pub fn start_key_rotation(
+        &mut self,
+        uid: &UID,
+        password: &str,
+        user_shares: Vec<(Share, ShareIndex)>,
+        totp_code: Option<u32>,
+    ) -> Result<KeyRotation, AuthError> {
+        let index = self.migrate_share_index(uid, password);
+        let (secret, _) = self.unlock_secret(uid, Some(password), user_shares, totp_code)?;
+        let old_key = bigint_to_key(&secret).ok_or(AuthError::InvalidShares)?;
+        let (new_key, new_pubkey) = generate_keypair();
+        Ok(KeyRotation {
+            uid: uid.clone(),
+            index,
+            password: Zeroizing::new(password.to_string()),
+            old_key: Zeroizing::new(old_key.to_vec()),
+            new_key,
+            new_pubkey,
+        })
+    }
+
+    // shares the new key in place of the old one, with the same password and
+    // config, and records its public key. All previously issued shares become
+    // stale, and the user's sessions are locked; the new user-held shares are
+    // returned. Shares the user holds as a trusted contact are re-encrypted to
+    // the new key.
+    pub fn finish_key_rotation(
+        &mut self,
+        rotation: KeyRotation,
+    ) -> Result<(Vec<(ShareRole, Share)>, ShareIndex), AuthError> {
+        let uid = &rotation.uid;
+        let store = self
+            .open_store(&rotation.index)?
+            .ok_or(AuthError::InvalidShares)?;
+        let mut held_shares = vec![];
+        for owner in self.get_contact_owners(uid) {
+            let social = &self.social_recoveries[&owner];
+            let (_, encshare) = social.shares.iter().find(|(c, _)| c == uid).unwrap();
+            let bytes = match decrypt_encdata(encshare, &rotation.old_key) {
+                (true, bytes) => Zeroizing::new(bytes),
+                _ => return Err(AuthError::InvalidShares),
+            };
+            held_shares.push((owner, encrypt_with_pubkey(&rotation.new_pubkey, &bytes)));
+        }
+        let config = store.config.clone();
+        let mut retired_commitments = store.retired_commitments.clone();
+        if !store.commitments.is_empty() {
+            retired_commitments.push(store.commitments.clone());
+        }
+
+        debug!("rotating key of {}", uid);
+        let secretkey_int = SecretInt(key_to_bigint(rotation.new_key.as_bytes()));
+        if let Some(social) = self.social_recoveries.get(uid) {
+            let contacts: Vec<UID> = social.shares.iter().map(|(c, _)| c.clone()).collect();
+            let required = social.required;
+            self.share_with_contacts(uid, &secretkey_int, &contacts, required, rotation.index)?;
+        }
+        let (shares, index) = self.share_secret(uid, &rotation.password, &secretkey_int, &config);
+        let mut store = self.open_store(&index)?.ok_or(AuthError::InvalidShares)?;
+        store.retired_commitments = retired_commitments;
+        self.seal_store(index, &store);
+        if let Some(creds) = self.user_creds.get_mut(uid) {
+            creds.pubkey = Some(rotation.new_pubkey.clone());
+        }
+        for (owner, encshare) in held_shares {
+            let social = self.social_recoveries.get_mut(&owner).unwrap();
+            if let Some((_, held)) = social.shares.iter_mut().find(|(c, _)| c == uid) {
+                *held = encshare;
+            }
+        }
+        self.lock_user(uid);
+        Ok((shares, index))
+    }
+
+    // re-randomizes the user's shares without changing their key, so that copies
+    // of the server's shares from before the refresh (e.g., in old backups) are
+    // useless. The user-held shares passed in are refreshed and returned in the
+    // same order; any that aren't passed in become stale.
+    pub fn refresh_shares(
+        &mut self,
+        uid: &UID,
+        password: &str,
+        user_shares: Vec<(Share, ShareIndex)>,
+        totp_code: Option<u32>,
+    ) -> Result<Vec<Share>, AuthError> {
+        let index = self.migrate_share_index(uid, password);
+        self.unlock_secret(uid, Some(password), user_shares.clone(), totp_code)?;
+        let mut store = self.open_store(&index)?.ok_or(AuthError::InvalidShares)?;
+        let prime = self.primes[&store.prime_id].clone();
+        let password_point = store
+            .password_kdf
+            .derive(password, &store.password_salt)
+            .ok_or(AuthError::InvalidShares)?;
+
+        // refresh every share at once so they stay consistent
+        let mut shares = vec![[modulus(&password_point, &prime), store.share_value.clone()]];
+        shares.extend(store.shares.iter().cloned());
+        shares.extend(user_shares.into_iter().map(|(share, _)| share));
+        let sss = ShamirSecretSharing {
+            threshold: store.config.required - 1,
+            share_count: shares.len(),
+            prime,
+        };
+        let (mut shares, mut delta) = sss.refresh(&shares);
+
+        debug!("refreshing shares for {}", uid);
+        let server_share_count = store.shares.len();
+        let group = self.groups.get(&store.prime_id).cloned();
+        let user_shares = shares.split_off(1 + server_share_count);
+        store.shares = shares.split_off(1);
+        store.share_value = shares.pop().unwrap()[1].clone();
+        if let (Some(group), false) = (group, store.commitments.is_empty()) {
+            let commitments = group.refresh_commitments(&store.commitments, &delta);
+            let retired = std::mem::replace(&mut store.commitments, commitments);
+            store.retired_commitments.push(retired);
+        }
+        self.seal_store(index, &store);
+        delta.iter_mut().for_each(zeroize_bigint);
+        Ok(user_shares)
+    }
+
+    // shares the user's key among trusted contacts (other registered users), any
+    // `required` of whom can together help the user recover their account (see
+    // recover_with_contacts). Each share is encrypted to its contact's public key.
+    // Replaces the user's previous contacts, if any.
+    pub fn set_trusted_contacts(
+        &mut self,
+        uid: &UID,
+        password: &str,
+        contacts: &[UID],
+        required: usize,
+        totp_code: Option<u32>,
+    ) -> Result<(), AuthError> {
+        let index = self.migrate_share_index(uid, password);
+        let (secret, _) = self.unlock_secret(uid, Some(password), vec![], totp_code)?;
+        self.share_with_contacts(uid, &secret, contacts, required, index)
+    }
+
+    // the users the contact holds shares for
+    pub fn get_contact_owners(&self, contact: &UID) -> Vec<UID> {
+        self.social_recoveries
+            .iter()
+            .filter(|(_, social)| social.shares.iter().any(|(c, _)| c == contact))
+            .map(|(owner, _)| owner.clone())
+            .collect()
+    }
+
+    // decrypts the share the contact holds for the owner with the contact's key,
+    // for the contact to hand to the owner during recovery
+    pub fn decrypt_contact_share(
+        &mut self,
+        contact: &UID,
+        password: &str,
+        owner: &UID,
+        totp_code: Option<u32>,
+    ) -> Result<Share, AuthError> {
+        let password = Some(password.to_string());
+        let key = self.get_priv_key_with_code(contact, password, vec![], totp_code)?;
+        let encshare = self
+            .social_recoveries
+            .get(owner)
+            .and_then(|social| social.shares.iter().find(|(c, _)| c == contact))
+            .map(|(_, encshare)| encshare)
+            .ok_or(AuthError::InvalidShares)?;
+        match decrypt_encdata(encshare, &key) {
+            (true, bytes) => {
+                let bytes = Zeroizing::new(bytes);
+                bincode::deserialize(&bytes).map_err(|_| AuthError::InvalidShares)
+            }
+            _ => Err(AuthError::InvalidShares),
+        }
+    }
+
+    // the recovery ceremony: rebuilds the user's key from shares decrypted by enough
+    // of their trusted contacts, and re-shares it under the new password like
+    // recover_account. The contacts' shares keep working.
+    pub fn recover_with_contacts(
+        &mut self,
+        uid: &UID,
+        contact_shares: Vec<Share>,
+        new_password: &str,
+    ) -> Result<(Vec<(ShareRole, Share)>, ShareIndex), AuthError> {
+        let social = self
+            .social_recoveries
+            .get(uid)
+            .ok_or(AuthError::InvalidShares)?;
+        let prime = self
+            .primes
+            .get(&social.prime_id)
+            .ok_or(AuthError::UnknownPrime)?;
+        let mut shares: Vec<Share> = vec![];
+        for share in contact_shares {
+            if !shares.iter().any(|s| s[0] == share[0]) {
+                shares.push(share);
+            }
+        }
+        let sss = ShamirSecretSharing {
+            threshold: social.required - 1,
+            share_count: shares.len(),
+            prime: prime.clone(),
+        };
+        let secret = match sss.reconstruct(&shares) {
+            Ok(secret) => SecretInt(secret),
+            Err(ShareError::TooFewShares) => return Err(AuthError::NotEnoughShares),
+            Err(_) => return Err(AuthError::InvalidShares),
+        };
+        if !self.secret_matches_pubkey(uid, &secret) {
+            return Err(AuthError::InvalidShares);
+        }
+        let index = social.index;
+        debug!("recovering {} with trusted contacts", uid);
+        self.replace_share_store(uid, index, &secret, new_password)
+    }
+
+    // reconstructs the user's key from the password (if given), any shares the
+    // user holds, and the shares held by the server
+    // shares, if the user has enrolled in TOTP, are only released with a code
+    // (see get_priv_key_with_totp)
+    pub fn get_priv_key(
+        &mut self,
+        uid: &UID,
+        password: Option<String>,
+        user_shares: Vec<(Share, ShareIndex)>,
+    ) -> Result<Zeroizing<Vec<u8>>, AuthError> {
+        self.get_priv_key_with_code(uid, password, user_shares, None)
+    }
+
+    // like get_priv_key, but releases the server's shares of a user enrolled in
+    // TOTP if the code is valid
+    pub fn get_priv_key_with_totp(
+        &mut self,
+        uid: &UID,
+        password: Option<String>,
+        user_shares: Vec<(Share, ShareIndex)>,
+        code: u32,
+    ) -> Result<Zeroizing<Vec<u8>>, AuthError> {
+        self.get_priv_key_with_code(uid, password, user_shares, Some(code))
+    }
+
+    // returns the seed for the user to add to their authenticator app. Once the
+    // user confirms they've saved it (see confirm_totp), the server's shares are
+    // only used with a valid code. Re-enrolling replaces the seed when confirmed.
+    pub fn enroll_totp(
+        &mut self,
+        uid: &UID,
+        password: &str,
+        code: Option<u32>,
+    ) -> Result<Zeroizing<Vec<u8>>, AuthError> {
+        self.migrate_share_index(uid, password);
+        self.unlock_secret(uid, Some(password), vec![], code)?;
+        let mut seed = Zeroizing::new(vec![0u8; TOTP_SEED_LEN]);
+        OsRng.fill_bytes(&mut seed);
+        let seed_key = self.totp_seed_key();
+        let encseed = encrypt_with_pubkey(&seed_key.public_key(), &seed);
+        self.pending_totp.insert(uid.clone(), encseed);
+        Ok(seed)
+    }
+
+    // makes the user's pending enrollment take effect, given a code from the new
+    // seed; fails with WrongCode if there's no pending enrollment
+    pub fn confirm_totp(&mut self, uid: &UID, code: u32) -> Result<(), AuthError> {
+        let now = self.clock.now();
+        self.check_lockout(uid, now)?;
+        let accepted = self
+            .pending_totp
+            .get(uid)
+            .and_then(|seed| self.accepted_totp_step(seed, None, code, now));
+        match accepted {
+            Some(step) => {
+                let seed = self.pending_totp.remove(uid).unwrap();
+                let enrollment = TotpEnrollment {
+                    seed,
+                    last_step: Some(step),
+                };
+                self.totp.insert(uid.clone(), enrollment);
+                Ok(())
+            }
+            None => {
+                debug!("wrong TOTP code confirming enrollment of {}", uid);
+                self.record_failed_unlock(uid, now);
+                Err(AuthError::WrongCode)
+            }
+        }
+    }
+
+    // how many 30 second steps a code may be ahead of or behind the clock
+    pub fn set_totp_skew(&mut self, steps: u64) {
+        self.totp_skew = steps;
+    }
+
+    fn get_priv_key_with_code(
+        &mut self,
+        uid: &UID,
+        password: Option<String>,
+        user_shares: Vec<(Share, ShareIndex)>,
+        code: Option<u32>,
+    ) -> Result<Zeroizing<Vec<u8>>, AuthError> {
+        let password = password.map(Zeroizing::new);
+        let password = password.as_ref().map(|password_str| password_str.as_str());
+        let index = password.map(|password_str| self.migrate_share_index(uid, password_str));
+        let (priv_key, shares) = self.unlock_secret(uid, password, user_shares, code)?;
+        if let (Some(index), Some(password_str)) = (index, password) {
+            self.upgrade_password_kdf(index, password_str, &shares);
+        }
+        let pkbytes = bigint_to_key(&priv_key).ok_or(AuthError::InvalidShares)?;
+        Ok(Zeroizing::new(pkbytes.to_vec()))
+    }
+
+    // reconstructs the user's key once, from the password and any shares the user
+    // holds, and caches it for the session TTL
+    pub fn unlock(
+        &mut self,
+        uid: &UID,
+        password: &str,
+        user_shares: Vec<(Share, ShareIndex)>,
+        totp_code: Option<u32>,
+    ) -> Result<SessionToken, AuthError> {
+        let password = Some(password.to_string());
+        let priv_key = self.get_priv_key_with_code(uid, password, user_shares, totp_code)?;
+        let now = self.clock.now();
+        self.sessions.retain(|_, session| session.expires_at > now);
+
+        let mut token = [0u8; 32];
+        OsRng.fill_bytes(&mut token);
+        let token = SessionToken(token);
+        self.sessions.insert(
+            token,
+            Session {
+                uid: uid.clone(),
+                priv_key,
+                expires_at: now + self.session_ttl,
+            },
+        );
+        Ok(token)
+    }
+
+    pub fn lock(&mut self, token: &SessionToken) {
+        self.sessions.remove(token);
+    }
+
+    // ends all of the user's sessions
+    pub fn lock_user(&mut self, uid: &UID) {
+        self.sessions.retain(|_, session| session.uid != *uid);
+    }
+
+    // the unlocked user and their key
+    pub fn get_session_key(
+        &mut self,
+        token: &SessionToken,
+    ) -> Result<(UID, Zeroizing<Vec<u8>>), AuthError> {
+        let now = self.clock.now();
+        match self.sessions.get(token) {
+            Some(session) if session.expires_at > now => {
+                Ok((session.uid.clone(), session.priv_key.clone()))
+            }
+            Some(_) => {
+                self.sessions.remove(token);
+                Err(AuthError::InvalidSession)
+            }
+            None => Err(AuthError::InvalidSession),
+        }
+    }
+
+    // applies to sessions unlocked after this is set
+    pub fn set_session_ttl(&mut self, ttl: Duration) {
+        self.session_ttl = ttl;
+    }
+
+    // failed unlocks are delayed with exponential backoff, and the user is locked
+    // out for a while after too many consecutive failures
+    pub fn set_lockout_policy(&mut self, policy: LockoutPolicy) {
+        self.lockout_policy = policy;
+    }
+
+    // e.g., so a user can be given another chance after being verified out of band
+    pub fn reset_failed_unlocks(&mut self, uid: &UID) {
+        self.failed_unlocks.remove(uid);
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    // reconstructs the secret, enforcing the lockout policy and checking the result
+    // against the user's public key. The server's shares of a user enrolled in TOTP
+    // are only used with a valid code.
+    fn unlock_secret(
+        &mut self,
+        uid: &UID,
+        password: Option<&str>,
+        user_shares: Vec<(Share, ShareIndex)>,
+        totp_code: Option<u32>,
+    ) -> Result<(SecretInt, Vec<Share>), AuthError> {
+        if !self.user_creds.contains_key(uid) {
+            return Err(AuthError::UnknownUser);
+        }
+        let now = self.clock.now();
+        self.check_lockout(uid, now)?;
+
+        let enrolled = self.totp.contains_key(uid);
+        let res = match totp_code {
+            Some(code) if enrolled && !self.verify_totp(uid, code, now) => {
+                Err(AuthError::WrongCode)
+            }
+            _ => {
+                let release_server_shares = !enrolled || totp_code.is_some();
+                match self.reconstruct_secret(uid, password, user_shares, release_server_shares) {
+                    Err(AuthError::NotEnoughShares) if !release_server_shares => {
+                        Err(AuthError::TotpRequired)
+                    }
+                    res => res,
+                }
+            }
+        };
+        let res = res.and_then(|(secret, shares)| {
+            // the password (if any) found the user's shares, so a key that
+            // doesn't match must come from bad shares
+            if self.secret_matches_pubkey(uid, &secret) {
+                Ok((secret, shares))
+            } else {
+                Err(AuthError::InvalidShares)
+            }
+        });
+        match res {
+            Err(AuthError::WrongPassword)
+            | Err(AuthError::WrongCode)
+            | Err(AuthError::InvalidShares)
+            | Err(AuthError::TamperedShare(_))
+            | Err(AuthError::StaleShare(_)) => self.record_failed_unlock(uid, now),
+            Ok(_) => {
+                self.failed_unlocks.remove(uid);
+            }
+            Err(_) => (),
+        }
+        res
+    }
+
+    fn check_lockout(&self, uid: &UID, now: SystemTime) -> Result<(), AuthError> {
+        match self.failed_unlocks.get(uid) {
+            Some(failed) if failed.retry_at > now => {
+                let retry_after = failed.retry_at.duration_since(now).unwrap_or_default();
+                Err(AuthError::Locked { retry_after })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record_failed_unlock(&mut self, uid: &UID, now: SystemTime) {
+        let failed = self
+            .failed_unlocks
+            .entry(uid.clone())
+            .or_insert(FailedUnlocks {
+                count: 0,
+                retry_at: now,
+            });
+        failed.count += 1;
+        failed.retry_at = now + self.lockout_policy.delay(failed.count);
+        debug!("failed unlock {} for {}", failed.count, uid);
+    }
+
+    // checks the code against the user's confirmed seed, and remembers its step
+    fn verify_totp(&mut self, uid: &UID, code: u32, now: SystemTime) -> bool {
+        let accepted = self
+            .totp
+            .get(uid)
+            .and_then(|e| self.accepted_totp_step(&e.seed, e.last_step, code, now));
+        match (accepted, self.totp.get_mut(uid)) {
+            (Some(step), Some(enrollment)) => {
+                enrollment.last_step = Some(step);
+                true
+            }
+            _ => {
+                debug!("wrong TOTP code for {}", uid);
+                false
+            }
+        }
+    }
+
+    // the step the code is for, if it's within the skew of the clock's and after
+    // the last accepted one
+    fn accepted_totp_step(
+        &self,
+        seed: &EncData,
+        last_step: Option<u64>,
+        code: u32,
+        now: SystemTime,
+    ) -> Option<u64> {
+        let seed_key = Zeroizing::new(self.totp_seed_key().as_bytes().to_vec());
+        let seed = match decrypt_encdata(seed, &seed_key) {
+            (true, seed) => Zeroizing::new(seed),
+            _ => return None,
+        };
+        let step = totp_step(now);
+        let skew = self.totp_skew;
+        (step.saturating_sub(skew)..=step.saturating_add(skew))
+            .filter(|s| last_step.is_none_or(|last| *s > last))
+            .find(|s| totp_code(&seed, *s) == code)
+    }
+
+    fn totp_seed_key(&self) -> SecretKey {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.locator_key)
+            .expect("HMAC can take a key of any size");
+        mac.update(TOTP_SEED_KEY_TAG);
+        let key: Zeroizing<[u8; 32]> = Zeroizing::new(mac.finalize().into_bytes().into());
+        SecretKey::from(*key)
+    }
+
+    // a wrong secret is usually too large to even be a key; beyond that, users
+    // registered before public keys were recorded can't be checked
+    fn secret_matches_pubkey(&self, uid: &UID, secret: &BigInt) -> bool {
+        let key = match bigint_to_key(secret) {
+            Some(key) => Zeroizing::new(key),
+            None => return false,
+        };
+        match self.get_pubkey(uid) {
+            Some(pubkey) => PublicKey::from(&SecretKey::from(*key)) == pubkey,
+            None => true,
+        }
+    }
+
+    // moves the password share to the current KDF, keeping the rest of the shares:
+    // the new password share is another point of the same polynomial
+    fn upgrade_password_kdf(&mut self, index: ShareIndex, password: &str, shares: &[Share]) {
+        let kdf = self.password_kdf.clone();
+        let mut store = match self.open_store(&index) {
+            Ok(Some(store)) if store.password_kdf != kdf => store,
+            _ => return,
+        };
+        let prime = match self.primes.get(&store.prime_id) {
+            Some(prime) => prime.clone(),
+            None => return,
+        };
+        let salt = SaltString::generate(&mut OsRng);
+        let point = match kdf.derive(password, salt.as_str()) {
+            Some(point) => SecretInt(modulus(&point, &prime)),
+            None => return,
+        };
+        if *point == BigInt::from(0) || shares.iter().any(|s| s[0] == *point) {
+            return;
+        }
+        let xs: Vec<BigInt> = shares.iter().map(|s| s[0].clone()).collect();
+        let ys: Vec<BigInt> = shares.iter().map(|s| s[1].clone()).collect();
+        let value = lagrange_interpolation_at(&xs, &ys, &point, &prime);
+
+        debug!("upgrading password KDF to {:?}", kdf);
+        store.share_value = value;
+        store.password_salt = salt.as_str().to_string();
+        store.password_kdf = kdf;
+        self.seal_store(index, &store);
+    }
+
+    fn reshare(
+        &mut self,
+        uid: &UID,
+        old_index: ShareIndex,
+        old_password: Option<&str>,
+        user_shares: Vec<(Share, ShareIndex)>,
+        new_password: &str,
+        totp_code: Option<u32>,
+    ) -> Result<(Vec<(ShareRole, Share)>, ShareIndex), AuthError> {
+        let (secretkey_int, _) = self.unlock_secret(uid, old_password, user_shares, totp_code)?;
+        self.replace_share_store(uid, old_index, &secretkey_int, new_password)
+    }
+
+    // shares the key under the new password with the old store's config, removing
+    // the old store
+    fn replace_share_store(
+        &mut self,
+        uid: &UID,
+        old_index: ShareIndex,
+        secretkey_int: &BigInt,
+        new_password: &str,
+    ) -> Result<(Vec<(ShareRole, Share)>, ShareIndex), AuthError> {
+        let config = match self.open_store(&old_index)? {
+            Some(store) => store.config.clone(),
+            None => return Err(AuthError::InvalidShares),
+        };
+
+        self.shares_map.remove(&old_index);
+        self.legacy_indices.retain(|_, index| *index != old_index);
+        let (shares, index) = self.share_secret(uid, new_password, secretkey_int, &config);
+        if let Some(social) = self.social_recoveries.get_mut(uid) {
+            social.index = index;
+        }
+        // sessions unlocked with the old password (possibly by someone else) end
+        self.lock_user(uid);
+        Ok((shares, index))
+    }
+
+    fn share_with_contacts(
+        &mut self,
+        uid: &UID,
+        secretkey_int: &BigInt,
+        contacts: &[UID],
+        required: usize,
+        index: ShareIndex,
+    ) -> Result<(), AuthError> {
+        if required < 2 || required > contacts.len() {
+            return Err(AuthError::InvalidThreshold);
+        }
+        // a contact holding two shares would count twice towards `required`
+        for (i, contact) in contacts.iter().enumerate() {
+            if contact == uid || contacts[..i].contains(contact) {
+                return Err(AuthError::InvalidContact(contact.clone()));
+            }
+        }
+        let pubkeys: Vec<PublicKey> = contacts
+            .iter()
+            .map(|contact| self.get_pubkey(contact))
+            .collect::<Option<_>>()
+            .ok_or(AuthError::UnknownUser)?;
+
+        // the contacts' shares are on their own polynomial, at random points
+        let prime = self.prime().clone();
+        let sss = ShamirSecretSharing {
+            threshold: required - 1,
+            share_count: contacts.len(),
+            prime: prime.clone(),
+        };
+        let first_point = OsRng.gen_bigint_range(&BigInt::from(1), &prime);
+        let shares = contacts
+            .iter()
+            .zip(pubkeys)
+            .zip(sss.share(secretkey_int, &first_point))
+            .map(|((contact, pubkey), share)| {
+                let bytes = Zeroizing::new(bincode::serialize(&share).unwrap());
+                (contact.clone(), encrypt_with_pubkey(&pubkey, &bytes))
+            })
+            .collect();
+        self.social_recoveries.insert(
+            uid.clone(),
+            SocialRecovery {
+                required,
+                prime_id: self.prime_id,
+                index,
+                shares,
+            },
+        );
+        Ok(())
+    }
+
+    fn share_secret(
+        &mut self,
+        uid: &UID,
+        password: &str,
+        secretkey_int: &BigInt,
+        config: &ShareConfig,
+    ) -> (Vec<(ShareRole, Share)>, ShareIndex) {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash_pass_bigint = self
+            .password_kdf
+            .derive(password, salt.as_str())
+            .expect("password KDF parameters were checked when it was set");
+
+        let sss = ShamirSecretSharing {
+            threshold: config.required - 1,
+            share_count: config.roles.len(),
+            prime: self.prime().clone(),
+        };
+        // returned format: vec < [h(p), f(h(p))], [rand1, f(rand1)], [rand2, f(rand2)], ... >
+        let (all_shares, commitments) = sss.share_verifiable(
+            secretkey_int,
+            &hash_pass_bigint,
+            &self.groups[&self.prime_id],
+        );
+
+        let uid_pw_hash = self.share_index(uid, password);
+        debug!("sharing key of {} at {:?}", uid, uid_pw_hash);
+
+        // the first share is at the password point; hand out the rest by role
+        let mut server_shares = vec![];
+        let mut other_shares = vec![];
+        let roles = config.roles.iter().filter(|r| **r != ShareRole::Password);
+        for (role, share) in roles.zip(all_shares[1..].iter()) {
+            match role {
+                ShareRole::Server => server_shares.push(share.clone()),
+                _ => other_shares.push((role.clone(), share.clone())),
+            }
+        }
+
+        // save share
+        let perm_share = ShareStore {
+            shares: server_shares,
+            share_value: all_shares[0][1].clone(),
+            password_salt: salt.as_str().to_string(),
+            password_kdf: self.password_kdf.clone(),
+            config: config.clone(),
+            prime_id: self.prime_id,
+            commitments,
+            retired_commitments: vec![],
+        };
+        // persist share info at share_loc
+        self.seal_store(uid_pw_hash, &perm_share);
+
+        (other_shares, uid_pw_hash)
+    }
+
+    // returns the secret along with the (distinct) shares it was reconstructed from
+    fn reconstruct_secret(
+        &self,
+        uid: &UID,
+        password: Option<&str>,
+        user_shares: Vec<(Share, ShareIndex)>,
+        release_server_shares: bool,
+    ) -> Result<(SecretInt, Vec<Share>), AuthError> {
+        let mut hash_pass = None;
+        let store;
+        let store_index;
+
+        if let Some(password_str) = password {
+            debug!("using uid and pw");
+            let uid_pw_hash = self.share_index(uid, password_str);
+            debug!("looking up shares of {} at {:?}", uid, uid_pw_hash);
+
+            let share = match self.open_store(&uid_pw_hash)? {
+                Some(share) => share,
+                None => {
+                    debug!("no shares stored for user and password");
+                    return Err(AuthError::WrongPassword);
+                }
+            };
+            let hash_pass_bigint = share
+                .password_kdf
+                .derive(password_str, &share.password_salt)
+                .ok_or(AuthError::InvalidShares)?;
+            hash_pass = Some(hash_pass_bigint);
+            store = share;
+            store_index = uid_pw_hash;
+        } else {
+            let index = match user_shares.first() {
+                Some((_, index)) => index,
+                None => return Err(AuthError::NotEnoughShares),
+            };
+            store_index = self.resolve_share_index(index);
+            store = match self.open_store(&store_index)? {
+                Some(store) => store,
+                None => {
+                    debug!("no shares stored at {:?}", index);
+                    return Err(AuthError::InvalidShares);
+                }
+            };
+        }
+
+        let prime = match self.primes.get(&store.prime_id) {
+            Some(prime) => prime,
+            None => {
+                debug!("shares made under unknown prime {:?}", store.prime_id);
+                return Err(AuthError::UnknownPrime);
+            }
+        };
+
+        // shares for another user (or another of the user's passwords) simply
+        // don't match; shares for this store that fail verification were tampered with
+        if user_shares
+            .iter()
+            .any(|(_, index)| self.resolve_share_index(index) != store_index)
+        {
+            debug!("user shares are for another share store");
+            return Err(AuthError::InvalidShares);
+        }
+        let password_share = hash_pass.map(|h| [modulus(&h, prime), store.share_value.clone()]);
+        let server_shares: &[Share] = match release_server_shares {
+            true => &store.shares,
+            false => &[],
+        };
+        if !store.commitments.is_empty() {
+            let group = self
+                .groups
+                .get(&store.prime_id)
+                .ok_or(AuthError::UnknownPrime)?;
+            let verify = |share: &Share, source: ShareSource| {
+                if group.verify(&store.commitments, share) {
+                    Ok(())
+                } else if store
+                    .retired_commitments
+                    .iter()
+                    .any(|commitments| group.verify(commitments, share))
+                {
+                    debug!("share {:?} is from before a refresh", source);
+                    Err(AuthError::StaleShare(source))
+                } else {
+                    debug!("share {:?} failed verification", source);
+                    Err(AuthError::TamperedShare(source))
+                }
+            };
+            if let Some(share) = &password_share {
+                verify(share, ShareSource::Password)?;
+            }
+            for (i, share) in server_shares.iter().enumerate() {
+                verify(share, ShareSource::Server(i))?;
+            }
+            for (i, (share, _)) in user_shares.iter().enumerate() {
+                verify(share, ShareSource::User(i))?;
+            }
+        }
+
+        let mut shares: Vec<[BigInt; 2]> = password_share.into_iter().collect();
+        debug!("getting users share");
+        // the same share may have been given twice; interpolation needs distinct points
+        for share in server_shares
+            .iter()
+            .cloned()
+            .chain(user_shares.into_iter().map(|(share, _)| share))
+        {
+            if !shares.iter().any(|s| s[0] == share[0]) {
+                shares.push(share);
+            }
+        }
+
+        let sss = ShamirSecretSharing {
+            threshold: store.config.required - 1,
+            share_count: shares.len(),
+            prime: prime.clone(),
+        };
+        match sss.reconstruct(&shares) {
+            Ok(secret) => Ok((SecretInt(secret), shares)),
+            Err(ShareError::TooFewShares) => {
+                debug!("Unable to reconstruct due to too few shares");
+                Err(AuthError::NotEnoughShares)
+            }
+            Err(e) => {
+                debug!("Unable to reconstruct: {:?}", e);
+                Err(AuthError::InvalidShares)
+            }
+        }
+    }
+
+    // the store at the index, if any, opened with the master key
+    fn open_store(&self, index: &ShareIndex) -> Result<Option<ShareStore>, AuthError> {
+        self.shares_map
+            .get(index)
+            .map(|sealed| self.master_key.open(sealed))
+            .transpose()
+    }
+
+    fn seal_store(&mut self, index: ShareIndex, store: &ShareStore) {
+        let sealed = self.master_key.seal(store);
+        self.shares_map.insert(index, sealed);
+    }
+
+    // where the server's shares for a user are stored
+    fn share_index(&self, uid: &UID, password: &str) -> ShareIndex {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.locator_key)
+            .expect("HMAC can take a key of any size");
+        mac.update(SHARE_LOCATOR_V1_TAG);
+        mac.update(&(uid.len() as u64).to_le_bytes());
+        mac.update(uid.as_bytes());
+        mac.update(password.as_bytes());
+        ShareIndex::V1(mac.finalize().into_bytes().into())
+    }
+
+    // the index under which a share store now lives, if it was migrated
+    fn resolve_share_index(&self, index: &ShareIndex) -> ShareIndex {
+        *self.legacy_indices.get(index).unwrap_or(index)
+    }
+
+    // moves the user's share store from a legacy locator to the current one,
+    // remembering the legacy locator so old user-held shares still find it
+    fn migrate_share_index(&mut self, uid: &UID, password: &str) -> ShareIndex {
+        let index = self.share_index(uid, password);
+        if !self.shares_map.contains_key(&index) {
+            let legacy_index = legacy_share_index(uid, password);
+            if let Some(store) = self.shares_map.remove(&legacy_index) {
+                debug!("migrating share store from {:?}", legacy_index);
+                self.shares_map.insert(index, store);
+                self.legacy_indices.insert(legacy_index, index);
+                if let Some(social) = self.social_recoveries.get_mut(uid) {
+                    social.index = index;
+                }
+            }
+        }
+        index
+    }
+}
+
+// a 32-byte key as the field element it is shared as (little-endian)
+pub fn key_to_bigint(key: &[u8; 32]) -> BigInt {
+    BigInt::from_bytes_le(num_bigint::Sign::Plus, key)
+}
+
+// the inverse of key_to_bigint; None if the value can't be a 32-byte key. The
+// key's trailing zero bytes aren't in the value's minimal encoding, so they're
+// restored here.
+pub fn bigint_to_key(value: &BigInt) -> Option<[u8; 32]> {
+    let (sign, bytes) = value.to_bytes_le();
+    let bytes = Zeroizing::new(bytes);
+    if sign == num_bigint::Sign::Minus || bytes.len() > 32 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    key[..bytes.len()].copy_from_slice(&bytes);
+    Some(key)
+}
+
+fn legacy_share_index(uid: &UID, password: &str) -> ShareIndex {
+    let mut uid_owned = uid.clone();
+    uid_owned.push_str(password);
+    let mut hasher = DefaultHasher::new();
+    uid_owned.hash(&mut hasher);
+    ShareIndex::Legacy(hasher.finish())
+}
+
+// the Mersenne prime 2^521 - 1, so that shares can be reconstructed across
+// restarts without having to persist the prime
+pub fn well_known_prime() -> BigInt {
+    (BigInt::from(1) << 521) - 1
+}
+
+// a random 512-bit prime; this takes a while, and the caller must persist it
+// (see Authorizer::new_with_prime and Authorizer::register_prime)
+pub fn generate_prime() -> BigInt {
+    loop {
+        let prime_gen = Generator::new_prime(512).to_bytes_le();
+        let try_pa: std::result::Result<[u8; 64], _> = prime_gen.try_into();
+        if let Ok(prime_arr) = try_pa {
+            return BigInt::from_bytes_le(num_bigint::Sign::Plus, &prime_arr);
+        }
+    }
+}
+
+pub fn prime_id(prime: &BigInt) -> PrimeId {
+    let digest = Sha256::digest(&prime.to_bytes_be().1);
+    digest[..8].try_into().unwrap()
+}
+
+// Miller-Rabin with random bases (num_primes' test rejects primes p with
+// 4 | p - 1, which the commitment group moduli always are)
+fn is_prime(n: &BigInt) -> bool {
+    let one = BigInt::from(1);
+    let two = BigInt::from(2);
+    if *n < BigInt::from(4) {
+        return *n > one;
+    }
+    let n_minus_one = n - &one;
+    let mut d = n_minus_one.clone();
+    let mut s = 0;
+    while (&d % &two) == BigInt::from(0) {
+        d /= &two;
+        s += 1;
+    }
+    'bases: for _ in 0..40 {
+        let mut x = OsRng.gen_bigint_range(&two, &n_minus_one).modpow(&d, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_minus_one {
+                continue 'bases;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+// commitments reveal the key to anyone who can take discrete logs mod the
+// modulus, so it must be at least this large
+pub const COMMITMENT_MODULUS_BITS: u64 = 2048;
+
+// the well-known prime's cofactor is the smallest one after min_cofactor's
+// for which k * (2^521 - 1) + 1 is prime
+const WELL_KNOWN_PRIME_COFACTOR_OFFSET: u32 = 52;
+
+// the smallest even k for which k * prime + 1 has COMMITMENT_MODULUS_BITS bits
+fn min_cofactor(prime: &BigInt) -> BigInt {
+    let k = (BigInt::from(1) << (COMMITMENT_MODULUS_BITS - 1)) / prime + 1;
+    &k + (&k % 2)
+}
+
+// the first even k from min_cofactor for which k * prime + 1 is prime
+fn find_cofactor(prime: &BigInt) -> BigInt {
+    let zero = BigInt::from(0);
+    let mut cofactor = min_cofactor(prime);
+    loop {
+        let candidate = &cofactor * prime + 1;
+        // trial division rules out most candidates cheaply
+        if (3u32..2000)
+            .step_by(2)
+            .all(|d| &candidate % d != zero || candidate == BigInt::from(d))
+            && is_prime(&candidate)
+        {
+            return cofactor;
+        }
+        cofactor += 2;
+    }
+}
+
+/*
+ * The subgroup of order `prime` of the integers mod `modulus`, where modulus is
+ * the prime k * prime + 1 of COMMITMENT_MODULUS_BITS bits. Feldman commitments
+ * to a sharing polynomial's coefficients are computed here, so that shares can
+ * be checked against them without revealing the polynomial.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitmentGroup {
+    pub prime: BigInt,
+    pub modulus: BigInt,
+    pub generator: BigInt,
+}
+
+impl CommitmentGroup {
+    // deterministic, so the group doesn't need to be persisted; finding the
+    // modulus takes a while for primes other than the well-known one
+    pub fn for_prime(prime: &BigInt) -> CommitmentGroup {
+        let cofactor = if *prime == well_known_prime() {
+            min_cofactor(prime) + WELL_KNOWN_PRIME_COFACTOR_OFFSET
+        } else {
+            find_cofactor(prime)
+        };
+        let modulus = &cofactor * prime + 1;
+        // raising to the cofactor lands in the subgroup, which any element
+        // other than 1 generates
+        let one = BigInt::from(1);
+        let generator = (2u32..)
+            .map(|h| BigInt::from(h).modpow(&cofactor, &modulus))
+            .find(|g| *g != one)
+            .unwrap();
+        CommitmentGroup {
+            prime: prime.clone(),
+            modulus,
+            generator,
+        }
+    }
+
+    // g^c for each coefficient c
+    pub fn commit(&self, coefficients: &[BigInt]) -> Vec<BigInt> {
+        coefficients
+            .iter()
+            .map(|c| self.generator.modpow(c, &self.modulus))
+            .collect()
+    }
+
+    // the commitments to f + delta, given those to f and delta's coefficients
+    pub fn refresh_commitments(&self, commitments: &[BigInt], delta: &[BigInt]) -> Vec<BigInt> {
+        commitments
+            .iter()
+            .zip(self.commit(delta))
+            .map(|(c, d)| c * d % &self.modulus)
+            .collect()
+    }
+
+    // whether g^f(x) = prod_j C_j^(x^j) for the share (x, f(x))
+    pub fn verify(&self, commitments: &[BigInt], share: &Share) -> bool {
+        let zero = BigInt::from(0);
+        let [x, y] = share;
+        if *x <= zero || *x >= self.prime || *y < zero || *y >= self.prime {
+            return false;
+        }
+        let mut expected = BigInt::from(1);
+        let mut power = BigInt::from(1);
+        for commitment in commitments {
+            expected = expected * commitment.modpow(&power, &self.modulus) % &self.modulus;
+            power = power * x % &self.prime;
+        }
+        self.generator.modpow(y, &self.modulus) == expected
+    }
+}
+
+/*
+ * SHAMIR SECRET SHARING STUFF
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShareError {
+    TooFewShares,
+    // a coordinate isn't in [0, prime)
+    OutOfField,
+    // the share at zero would be the secret itself
+    ZeroPoint,
+    DuplicatePoint,
+    // the share at this position doesn't match the commitments
+    CommitmentMismatch(usize),
+    // byte shares of secrets of different lengths
+    LengthMismatch,
+    // a reconstructed chunk of a byte secret isn't validly encoded, so some share is wrong
+    InvalidChunk,
+}
+
+pub struct ShamirSecretSharing {
+    /// Maximum number of shares that can be known without exposing the secret.
+    pub threshold: usize,
+    /// Number of shares to split the secret into.
+    pub share_count: usize,
+    /// Prime defining the Zp field in which computation is taking place.
+    pub prime: BigInt,
+}
+
+impl ShamirSecretSharing {
+    /// Minimum number of shares required to reconstruct secret.
+    ///
+    /// For this scheme this is always `threshold + 1`.
+    pub fn reconstruct_limit(&self) -> usize {
+        self.threshold + 1
+    }
+
+    /// Generate `share_count` shares from `secret`.
+    ///
+    /// The first share is at `hash_pass` (reduced into the field); the others are at
+    /// distinct, nonzero points sampled uniformly from the field.
+    pub fn share(&self, secret: &BigInt, hash_pass: &BigInt) -> Vec<[BigInt; 2]> {
+        assert!(
+            *secret >= BigInt::from(0) && *secret < self.prime,
+            "secret must be an element of the field"
+        );
+        let mut result = self.sample_polynomial(secret, hash_pass);
+        let shares = self.evaluate_polynomial(&result[0], &result[1]);
+        result[0].iter_mut().for_each(zeroize_bigint);
+        shares
+    }
+
+    /// Like `share`, but also returns Feldman commitments to the polynomial's
+    /// coefficients, against which each share can be verified.
+    pub fn share_verifiable(
+        &self,
+        secret: &BigInt,
+        hash_pass: &BigInt,
+        group: &CommitmentGroup,
+    ) -> (Vec<[BigInt; 2]>, Vec<BigInt>) {
+        assert!(group.prime == self.prime, "group is for another field");
+        assert!(
+            *secret >= BigInt::from(0) && *secret < self.prime,
+            "secret must be an element of the field"
+        );
+        let mut result = self.sample_polynomial(secret, hash_pass);
+        let commitments = group.commit(&result[0]);
+        let shares = self.evaluate_polynomial(&result[0], &result[1]);
+        result[0].iter_mut().for_each(zeroize_bigint);
+        (shares, commitments)
+    }
+
+    /// Like `reconstruct`, but first checks every share against the commitments.
+    pub fn reconstruct_verified(
+        &self,
+        shares: &[[BigInt; 2]],
+        commitments: &[BigInt],
+        group: &CommitmentGroup,
+    ) -> Result<BigInt, ShareError> {
+        if let Some(i) = shares.iter().position(|s| !group.verify(commitments, s)) {
+            return Err(ShareError::CommitmentMismatch(i));
+        }
+        self.reconstruct(shares)
+    }
+
+    /// Re-randomize shares without changing the secret, by adding a random polynomial
+    /// with a zero constant term to each share.
+    ///
+    /// Returns the new shares along with the added polynomial's coefficients (for
+    /// `CommitmentGroup::refresh_commitments`). Shares that aren't refreshed together
+    /// are no longer consistent with the new ones.
+    pub fn refresh(&self, shares: &[[BigInt; 2]]) -> (Vec<[BigInt; 2]>, Vec<BigInt>) {
+        let zero = BigInt::from(0);
+        let mut delta = vec![zero.clone()];
+        delta.extend((0..self.threshold).map(|_| OsRng.gen_bigint_range(&zero, &self.prime)));
+        let shares = shares
+            .iter()
+            .map(|[x, y]| {
+                let y_delta = mod_evaluate_polynomial(&delta, x, &self.prime);
+                [x.clone(), (y + y_delta) % &self.prime]
+            })
+            .collect();
+        (shares, delta)
+    }
+
+    /// Reconstruct `secret` from a large enough subset of the shares.
+    ///
+    /// Every share must be a point of the field with a nonzero x-coordinate, and no two
+    /// shares may have the same x-coordinate; there must be at least `reconstruct_limit`.
+    pub fn reconstruct(&self, shares: &[[BigInt; 2]]) -> Result<BigInt, ShareError> {
+        if shares.len() < self.reconstruct_limit() {
+            return Err(ShareError::TooFewShares);
+        }
+        let zero = BigInt::from(0);
+        for (i, [x, y]) in shares.iter().enumerate() {
+            if *x < zero || *x >= self.prime || *y < zero || *y >= self.prime {
+                return Err(ShareError::OutOfField);
+            }
+            if *x == zero {
+                return Err(ShareError::ZeroPoint);
+            }
+            if shares[..i].iter().any(|s| s[0] == *x) {
+                return Err(ShareError::DuplicatePoint);
+            }
+        }
+        let x_values: Vec<BigInt> = (0..self.reconstruct_limit())
+            .map(|i| shares[i][0].clone())
+            .collect();
+        let y_values: Vec<BigInt> = (0..self.reconstruct_limit())
+            .map(|i| shares[i][1].clone())
+            .collect();
+        Ok(lagrange_interpolation_at_zero(
+            &x_values,
+            &y_values,
+            &self.prime,
+        ))
+    }
+
+    fn sample_polynomial(&self, zero_value: &BigInt, hash_pass: &BigInt) -> [Vec<BigInt>; 2] {
+        [
+            self.sample_coefficients(zero_value),
+            self.sample_points(hash_pass),
+        ]
+    }
+
+    fn sample_coefficients(&self, zero_value: &BigInt) -> Vec<BigInt> {
+        let zero = BigInt::from(0);
+        // fix the first coefficient (corresponding to the evaluation at zero)
+        let mut coefficients = vec![zero_value.clone()];
+        // sample the remaining coefficients uniformly from Zp using secure randomness
+        let mut rng = OsRng;
+        let random_coefficients: Vec<BigInt> = (0..self.threshold)
+            .map(|_| rng.gen_bigint_range(&zero, &self.prime))
+            .collect();
+        coefficients.extend(random_coefficients);
+        coefficients
+    }
+
+    fn sample_points(&self, hash_pass: &BigInt) -> Vec<BigInt> {
+        let zero = BigInt::from(0);
+        let one = BigInt::from(1);
+        // a share at zero would be the secret itself
+        let password_point = modulus(hash_pass, &self.prime);
+        assert!(
+            password_point != zero,
+            "password point is zero in the field"
+        );
+        let mut points = vec![password_point];
+        let mut rng = OsRng;
+        while points.len() < self.share_count {
+            let point = rng.gen_bigint_range(&one, &self.prime);
+            if !points.contains(&point) {
+                points.push(point);
+            }
+        }
+        points
+    }
+
+    fn evaluate_polynomial(&self, coefficients: &[BigInt], points: &[BigInt]) -> Vec<[BigInt; 2]> {
+        // evaluate at all points
+        let mut result: Vec<[BigInt; 2]> = vec![];
+        for point in points {
+            let y_val = mod_evaluate_polynomial(coefficients, point, &self.prime);
+            result.push([point.clone(), y_val.clone()]);
+        }
+        result
+    }
+}
+
+/// A share of a byte secret: a point, and the value there of each chunk's polynomial.
+pub type ByteShare = (BigInt, Vec<BigInt>);
+
+/// Shamir secret sharing of secrets of any length over the same prime field.
+///
+/// The secret is split into chunks small enough to be field elements, each shared
+/// with its own polynomial, but at the same points for every chunk. Each chunk is
+/// prefixed with a 1 byte before conversion so its leading zeros survive, which
+/// also lets reconstruction catch most wrong shares.
+pub struct ByteSecretSharing {
+    /// Maximum number of shares that can be known without exposing the secret.
+    pub threshold: usize,
+    /// Number of shares to split the secret into.
+    pub share_count: usize,
+    /// Prime defining the Zp field in which computation is taking place.
+    pub prime: BigInt,
+}
+
+impl ByteSecretSharing {
+    /// Minimum number of shares required to reconstruct secret.
+    pub fn reconstruct_limit(&self) -> usize {
+        self.threshold + 1
+    }
+
+    /// Number of bytes of the secret in each chunk.
+    pub fn chunk_len(&self) -> usize {
+        // the prefixed chunk must be less than the prime
+        let chunk_len = ((self.prime.bits() - 1) / 8) as usize - 1;
+        assert!(chunk_len > 0, "prime is too small to share bytes");
+        chunk_len
+    }
+
+    /// Generate `share_count` shares from `secret`, the first at `hash_pass`.
+    pub fn share(&self, secret: &[u8], hash_pass: &BigInt) -> Vec<ByteShare> {
+        let sss = self.field_sharing();
+        let points = sss.sample_points(hash_pass);
+        let mut shares: Vec<ByteShare> = points.iter().map(|x| (x.clone(), vec![])).collect();
+        for chunk in secret.chunks(self.chunk_len()) {
+            let mut prefixed = Zeroizing::new(vec![1]);
+            prefixed.extend_from_slice(chunk);
+            let value = SecretInt(BigInt::from_bytes_be(num_bigint::Sign::Plus, &prefixed));
+            let mut coefficients = sss.sample_coefficients(&value);
+            for ([_, y], (_, ys)) in sss
+                .evaluate_polynomial(&coefficients, &points)
+                .into_iter()
+                .zip(shares.iter_mut())
+            {
+                ys.push(y);
+            }
+            coefficients.iter_mut().for_each(zeroize_bigint);
+        }
+        shares
+    }
+
+    /// Reconstruct `secret` from a large enough subset of the shares, which must
+    /// satisfy the same conditions as for `ShamirSecretSharing::reconstruct`.
+    pub fn reconstruct(&self, shares: &[ByteShare]) -> Result<Zeroizing<Vec<u8>>, ShareError> {
+        // checked up front, as an empty secret has no chunks to reconstruct
+        if shares.len() < self.reconstruct_limit() {
+            return Err(ShareError::TooFewShares);
+        }
+        let chunk_count = shares[0].1.len();
+        if shares.iter().any(|(_, ys)| ys.len() != chunk_count) {
+            return Err(ShareError::LengthMismatch);
+        }
+        let sss = self.field_sharing();
+        let mut secret = Zeroizing::new(vec![]);
+        for i in 0..chunk_count {
+            let chunk_shares: Vec<Share> = shares
+                .iter()
+                .map(|(x, ys)| [x.clone(), ys[i].clone()])
+                .collect();
+            let chunk = SecretInt(sss.reconstruct(&chunk_shares)?);
+            let prefixed = Zeroizing::new(chunk.to_bytes_be().1);
+            let is_last = i + 1 == chunk_count;
+            if prefixed[0] != 1
+                || prefixed.len() > self.chunk_len() + 1
+                || (!is_last && prefixed.len() != self.chunk_len() + 1)
+            {
+                return Err(ShareError::InvalidChunk);
+            }
+            secret.extend_from_slice(&prefixed[1..]);
+        }
+        Ok(secret)
+    }
+
+    fn field_sharing(&self) -> ShamirSecretSharing {
+        ShamirSecretSharing {
+            threshold: self.threshold,
+            share_count: self.share_count,
+            prime: self.prime.clone(),
+        }
+    }
+}
+
+pub fn mod_evaluate_polynomial(coefficients: &[BigInt], point: &BigInt, prime: &BigInt) -> BigInt {
+    // evaluate using Horner's rule
+    //  - to combine with fold we consider the coefficients in reverse order
+    let mut reversed_coefficients = coefficients.iter().rev();
+    // manually split due to fold insisting on an initial value
+    let head = reversed_coefficients.next().unwrap();
+    let tail = reversed_coefficients;
+    tail.fold(head.clone(), |partial, coef| {
+        (partial * point + coef) % prime
+    })
+}
+
+pub fn gcd(a: &BigInt, b: &BigInt) -> (BigInt, BigInt, BigInt) {
+    if *b == BigInt::from(0) {
+        (a.clone(), BigInt::from(1), BigInt::from(0))
+    } else {
+        let n = a.checked_div(b).expect("dividing overflowed");
+        let c = a % b;
+        let r = gcd(b, &c);
+        (r.0, r.2.clone(), r.1 - r.2.clone() * n)
+    }
+}
+
+/// Inverse of `k` in the *Zp* field defined by `prime`.
+pub fn mod_inverse(k: &BigInt, prime: &BigInt) -> BigInt {
+    let k2 = k % prime;
+    let r = if k2 < BigInt::from(0) {
+        -gcd(prime, &-k2).2
+    } else {
+        gcd(prime, &k2).2
+    };
+    (prime + r) % prime
+}
+
+pub fn lagrange_interpolation_at_zero(
+    points: &[BigInt],
+    values: &[BigInt],
+    prime: &BigInt,
+) -> BigInt {
+    assert_eq!(points.len(), values.len());
+    // Lagrange interpolation for point 0
+    let mut acc = BigInt::from(0);
+    for i in 0..values.len() {
+        let xi = &points[i];
+        let yi = &values[i];
+        let mut num = BigInt::from(1);
+        let mut denum = BigInt::from(1);
+        for j in 0..values.len() {
+            if j != i {
+                let xj = &points[j];
+                let num_xj_prod = num.checked_mul(xj).expect("multiplication overflow");
+                num = modulus(&num_xj_prod, &prime);
+                let xj_xi_diff = xj.checked_sub(xi).expect("subtraction overflow");
+                let dnum_diff_prod = denum
+                    .checked_mul(&xj_xi_diff)
+                    .expect("second multiplication overflow");
+                denum = modulus(&dnum_diff_prod, prime);
+            }
+        }
+        let mod_inv = mod_inverse(&denum, &prime);
+        let prod1 = yi.checked_mul(&num).expect("multiplication overflow");
+        let prod2 = prod1
+            .checked_mul(&mod_inv)
+            .expect("multiplication overflow");
+        let sum = acc.checked_add(&prod2).expect("addition overflow");
+        acc = sum % prime;
+    }
+    acc
+}
+
+// evaluates the polynomial through the given points at `at`
+pub fn lagrange_interpolation_at(
+    points: &[BigInt],
+    values: &[BigInt],
+    at: &BigInt,
+    prime: &BigInt,
+) -> BigInt {
+    assert_eq!(points.len(), values.len());
+    let mut acc = BigInt::from(0);
+    for i in 0..values.len() {
+        let mut num = BigInt::from(1);
+        let mut denum = BigInt::from(1);
+        for j in 0..values.len() {
+            if j != i {
+                num = modulus(&(num * (at - &points[j])), prime);
+                denum = modulus(&(denum * (&points[i] - &points[j])), prime);
+            }
+        }
+        let term = &values[i] * num * mod_inverse(&denum, prime);
+        acc = modulus(&(acc + term), prime);
+    }
+    acc
+}
+
+fn modulus(a: &BigInt, m: &BigInt) -> BigInt {
+    ((a % m) + m) % m
 }
 
 // tests
 
 #[test]
-fn test_lagrange_interpolation_at_zero() {
-    let res1 = lagrange_interpolation_at_zero(
+fn test_lagrange_interpolation_at_zero() {
+    let res1 = lagrange_interpolation_at_zero(
+        &[BigInt::from(1), BigInt::from(2)],
+        &[BigInt::from(4), BigInt::from(5)],
+        &BigInt::from(1613),
+    );
+
+    assert_eq!(res1, BigInt::from(3))
+}
+
+#[test]
+fn test_evaluate_polynomial() {
+    use num_primes::Generator;
+    let secretkey_int = BigInt::from(1234);
+    let hash_pass = BigInt::from(5);
+
+    let prime_gen = Generator::new_prime(256).to_bytes_le();
+    let prime_arr: [u8; 32] = prime_gen
+        .try_into()
+        .expect("Could not turn u64 vec into bytes?");
+    let prime = BigInt::from_bytes_le(num_bigint::Sign::Plus, &prime_arr);
+
+    let ref tss = ShamirSecretSharing {
+        threshold: 1,
+        share_count: 3,
+        prime: prime,
+    };
+    let shares = tss.share(&secretkey_int, &hash_pass);
+
+    assert_eq!(
+        tss.reconstruct(&(shares[..2].to_vec())),
+        Ok(BigInt::from(1234))
+    );
+}
+
+#[test]
+fn simple_realistic_test() {
+    use num_primes::Generator;
+    use rand::rngs::OsRng;
+    let mut rng = OsRng;
+
+    let secretkey = SecretKey::generate(&mut rng);
+    let secretkey_int = BigInt::from_bytes_le(num_bigint::Sign::Plus, secretkey.as_bytes());
+
+    let hash_pass = BigInt::from(524736458);
+
+    let prime_gen = Generator::new_prime(512).to_bytes_le();
+    let prime_arr: [u8; 64] = prime_gen
+        .try_into()
+        .expect("Could not turn u64 vec into bytes?");
+    let prime = BigInt::from_bytes_le(num_bigint::Sign::Plus, &prime_arr);
+
+    let sss = ShamirSecretSharing {
+        threshold: 1,
+        share_count: 3,
+        prime: prime,
+    };
+
+    let all_shares = sss.share(&secretkey_int, &hash_pass);
+
+    assert_eq!(
+        sss.reconstruct(&(all_shares[..2].to_vec())),
+        Ok(secretkey_int)
+    );
+}
+
+#[test]
+fn realistic_test() {
+    use num_primes::Generator;
+    use pbkdf2::{
+        password_hash::{PasswordHasher, SaltString},
+        Pbkdf2,
+    };
+    use rand::rngs::OsRng;
+
+    // --------------- construction ---------------
+
+    let mut rng = OsRng;
+
+    let secretkey = SecretKey::generate(&mut rng);
+    let secretkey_int = BigInt::from_bytes_le(num_bigint::Sign::Plus, secretkey.as_bytes());
+
+    let password = String::from("password");
+
+    let salt = SaltString::generate(&mut rng);
+    let _pass_info: String = Pbkdf2
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+    // let hash_pass_bigint = BigInt::from_bytes_le(num_bigint::Sign::Plus, pass_info.as_bytes());
+    let hash_pass_bigint = BigInt::from(123456789);
+
+    let prime_gen = Generator::new_prime(512).to_bytes_le();
+    let prime_arr: [u8; 64] = prime_gen
+        .try_into()
+        .expect("Could not turn u64 vec into bytes?");
+    let prime = BigInt::from_bytes_le(num_bigint::Sign::Plus, &prime_arr);
+
+    println!("prime: {}", prime.clone());
+
+    let sss = ShamirSecretSharing {
+        threshold: 1,
+        share_count: 3,
+        prime: prime.clone(),
+    };
+
+    let all_shares = sss.share(&secretkey_int, &hash_pass_bigint);
+
+    // --------------- reconstruction ---------------
+
+    let mut recon_shares: Vec<[BigInt; 2]> = vec![];
+
+    let edna_share = all_shares[1].clone();
+    let edna_share_value = all_shares[0][1].clone();
+
+    let _pass_info2: String = Pbkdf2
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+    // let hash_pass_bigint2 = BigInt::from_bytes_le(num_bigint::Sign::Plus, pass_info2.as_bytes());
+    let hash_pass_bigint2 = BigInt::from(123456789);
+
+    let derived_share = [hash_pass_bigint2, edna_share_value.clone()];
+
+    recon_shares.push(edna_share);
+    recon_shares.push(derived_share);
+
+    println!("share 0: {}, {}", recon_shares[0][0], recon_shares[0][1]);
+    println!("share 1: {}, {}", recon_shares[1][0], recon_shares[1][1]);
+
+    // --------------- assert equal ---------------
+
+    println!("checking fake reconstruction");
+    let alleged_priv_key = sss.reconstruct(&(all_shares[..2].to_vec())).unwrap();
+    println!("private key: {}", secretkey_int);
+    println!("found private key: {}", alleged_priv_key);
+    assert!(secretkey_int.eq(&alleged_priv_key));
+
+    let recon_priv_key = sss.reconstruct(&recon_shares).unwrap();
+    println!("checking actual reconstruction");
+    println!("private key: {}", secretkey_int);
+    println!("found private key: {}", recon_priv_key);
+    assert!(secretkey_int.eq(&recon_priv_key));
+}
+
+#[test]
+fn test_k_of_n_share_config() {
+    use ShareRole::*;
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let password = "password";
+
+    // 2-of-4: the password alone (with the server's share) suffices,
+    // as do the backup code and trusted contact together
+    let config = ShareConfig::new(2, vec![Password, Server, BackupCode, TrustedContact]).unwrap();
+    let (shares, index) = authorizer.register_user_shares_with_config(&uid, password, &config);
+    assert_eq!(shares.len(), 2);
+    assert_eq!(shares[0].0, BackupCode);
+    assert_eq!(shares[1].0, TrustedContact);
+    let key = authorizer
+        .get_priv_key(&uid, Some(password.to_string()), vec![])
+        .unwrap();
+    let from_shares = authorizer
+        .get_priv_key(
+            &uid,
+            None,
+            shares.iter().map(|(_, s)| (s.clone(), index)).collect(),
+        )
+        .unwrap();
+    assert_eq!(key, from_shares);
+
+    // 3-of-4: the password now needs one of the other shares too
+    let uid = "bob".to_string();
+    let config = ShareConfig::new(3, vec![Password, Server, BackupCode, TrustedContact]).unwrap();
+    let (shares, index) = authorizer.register_user_shares_with_config(&uid, password, &config);
+    assert!(authorizer
+        .get_priv_key(&uid, Some(password.to_string()), vec![])
+        .is_err());
+    let key = authorizer
+        .get_priv_key(
+            &uid,
+            Some(password.to_string()),
+            vec![(shares[0].1.clone(), index)],
+        )
+        .unwrap();
+    let from_shares = authorizer
+        .get_priv_key(
+            &uid,
+            None,
+            shares.iter().map(|(_, s)| (s.clone(), index)).collect(),
+        )
+        .unwrap();
+    assert_eq!(key, from_shares);
+
+    // the server can't hold enough shares to reconstruct by itself
+    assert!(ShareConfig::new(2, vec![Password, Server, Server]).is_none());
+    assert!(ShareConfig::new(2, vec![Server, User]).is_none());
+}
+
+#[test]
+fn test_change_password() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let (user_share, index) = authorizer.register_user_shares(&uid, "old");
+    let key = authorizer
+        .get_priv_key(&uid, Some("old".to_string()), vec![])
+        .unwrap();
+
+    assert_eq!(
+        authorizer.change_password(&uid, "wrong", "new", vec![], None),
+        Err(AuthError::WrongPassword)
+    );
+    authorizer.reset_failed_unlocks(&uid);
+    let (new_shares, new_index) = authorizer
+        .change_password(&uid, "old", "new", vec![], None)
+        .unwrap();
+
+    // same key under the new password and the new user share
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("new".to_string()), vec![]),
+        Ok(key.clone())
+    );
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(new_shares[0].1.clone(), new_index)]),
+        Ok(key)
+    );
+
+    // the old password and old user share no longer work
+    assert!(authorizer
+        .get_priv_key(&uid, Some("old".to_string()), vec![])
+        .is_err());
+    assert!(authorizer
+        .get_priv_key(&uid, None, vec![(user_share, index)])
+        .is_err());
+}
+
+#[test]
+fn test_recover_account() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let (recovery_share, index) = authorizer.register_user_shares(&uid, "forgotten");
+    let key = authorizer
+        .get_priv_key(&uid, Some("forgotten".to_string()), vec![])
+        .unwrap();
+
+    let (new_shares, new_index) = authorizer
+        .recover_account(&uid, vec![(recovery_share.clone(), index)], "new", None)
+        .unwrap();
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("new".to_string()), vec![]),
+        Ok(key.clone())
+    );
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(new_shares[0].1.clone(), new_index)]),
+        Ok(key)
+    );
+
+    // the used recovery share can't be used again
+    assert!(authorizer
+        .recover_account(&uid, vec![(recovery_share, index)], "other", None)
+        .is_err());
+    assert!(authorizer
+        .get_priv_key(&uid, Some("forgotten".to_string()), vec![])
+        .is_err());
+}
+
+#[test]
+fn test_share_locators() {
+    let uid = "alice".to_string();
+    let a = Authorizer::new_with_locator_key(b"server secret");
+    let b = Authorizer::new_with_locator_key(b"server secret");
+    let c = Authorizer::new_with_locator_key(b"other secret");
+    assert_eq!(a.share_index(&uid, "pw"), b.share_index(&uid, "pw"));
+    assert_ne!(a.share_index(&uid, "pw"), c.share_index(&uid, "pw"));
+    // the uid and password can't be shifted into each other
+    assert_ne!(
+        a.share_index(&"alic".to_string(), "epw"),
+        a.share_index(&uid, "pw")
+    );
+}
+
+#[test]
+fn test_migrate_legacy_share_index() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let (user_share, index) = authorizer.register_user_shares(&uid, "password");
+    let key = authorizer
+        .get_priv_key(&uid, Some("password".to_string()), vec![])
+        .unwrap();
+
+    // pretend the share store was saved under a legacy locator
+    let legacy_index = legacy_share_index(&uid, "password");
+    let store = authorizer.shares_map.remove(&index).unwrap();
+    authorizer.shares_map.insert(legacy_index, store);
+
+    // the legacy user share still works before and after the migration
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(user_share.clone(), legacy_index)]),
+        Ok(key.clone())
+    );
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("password".to_string()), vec![]),
+        Ok(key.clone())
+    );
+    assert!(authorizer.shares_map.contains_key(&index));
+    assert!(!authorizer.shares_map.contains_key(&legacy_index));
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(user_share, legacy_index)]),
+        Ok(key)
+    );
+}
+
+#[test]
+fn test_shares_record_prime() {
+    let old_prime = generate_prime();
+    let mut old = Authorizer::new_with_prime(b"server secret", old_prime.clone());
+    let uid = "alice".to_string();
+    old.register_user_shares(&uid, "password");
+    let key = old.get_priv_key(&uid, Some("password".to_string()), vec![]);
+    assert!(key.is_ok());
+
+    // a restarted authorizer with the well-known prime needs the old prime
+    // registered to reconstruct the old shares
+    let mut restarted = Authorizer::new_with_keys(b"server secret", old.master_key.clone());
+    restarted.shares_map = old.shares_map.clone();
+    restarted.user_creds = old.user_creds.clone();
+    assert!(restarted
+        .get_priv_key(&uid, Some("password".to_string()), vec![])
+        .is_err());
+    assert_eq!(restarted.register_prime(old_prime), old.prime_id);
+    assert_eq!(
+        restarted.get_priv_key(&uid, Some("password".to_string()), vec![]),
+        key
+    );
+
+    // changing the password moves the shares to the current prime
+    restarted
+        .change_password(&uid, "password", "new", vec![], None)
+        .unwrap();
+    let index = restarted.share_index(&uid, "new");
+    assert_eq!(
+        restarted.open_store(&index).unwrap().unwrap().prime_id,
+        prime_id(&well_known_prime())
+    );
+    assert_eq!(
+        restarted.get_priv_key(&uid, Some("new".to_string()), vec![]),
+        key
+    );
+}
+
+#[test]
+fn test_shares_well_formed() {
+    let prime = well_known_prime();
+    let sss = ShamirSecretSharing {
+        threshold: 2,
+        share_count: 20,
+        prime: prime.clone(),
+    };
+    let secret = BigInt::from(1234);
+    // the password point is reduced into the field
+    let hash_pass = &prime + BigInt::from(5);
+    let shares = sss.share(&secret, &hash_pass);
+    assert_eq!(shares.len(), 20);
+    assert_eq!(shares[0][0], BigInt::from(5));
+    for (i, [x, y]) in shares.iter().enumerate() {
+        assert!(*x > BigInt::from(0) && *x < prime);
+        assert!(*y >= BigInt::from(0) && *y < prime);
+        assert!(shares[..i].iter().all(|s| s[0] != *x));
+    }
+    assert_eq!(sss.reconstruct(&shares[5..8]), Ok(secret));
+}
+
+#[test]
+fn test_reconstruct_rejects_malformed_shares() {
+    let prime = well_known_prime();
+    let sss = ShamirSecretSharing {
+        threshold: 1,
+        share_count: 3,
+        prime: prime.clone(),
+    };
+    let shares = sss.share(&BigInt::from(1234), &BigInt::from(5));
+
+    assert_eq!(sss.reconstruct(&shares[..1]), Err(ShareError::TooFewShares));
+    assert_eq!(
+        sss.reconstruct(&[shares[0].clone(), shares[0].clone()]),
+        Err(ShareError::DuplicatePoint)
+    );
+    assert_eq!(
+        sss.reconstruct(&[shares[0].clone(), [BigInt::from(0), BigInt::from(1234)]]),
+        Err(ShareError::ZeroPoint)
+    );
+    assert_eq!(
+        sss.reconstruct(&[shares[0].clone(), [prime.clone(), shares[1][1].clone()]]),
+        Err(ShareError::OutOfField)
+    );
+    assert_eq!(
+        sss.reconstruct(&[shares[0].clone(), [shares[1][0].clone(), BigInt::from(-1)]]),
+        Err(ShareError::OutOfField)
+    );
+}
+
+#[test]
+fn test_encrypt_to_registered_user() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    assert!(authorizer.get_pubkey(&uid).is_none());
+    authorizer.register_user_shares(&uid, "password");
+    assert!(!authorizer.get_user_creds(&uid).unwrap().is_anon);
+
+    // encrypt while the user is offline, then decrypt with their password
+    let pubkey = authorizer.get_pubkey(&uid).unwrap();
+    let plaintext = b"undo record".to_vec();
+    let encdata = encrypt_with_pubkey(&pubkey, &plaintext);
+    let privkey = authorizer
+        .get_priv_key(&uid, Some("password".to_string()), vec![])
+        .unwrap();
+    assert_eq!(decrypt_encdata(&encdata, &privkey), (true, plaintext));
+}
+
+#[test]
+fn test_lagrange_interpolation_at() {
+    // the line through (1, 4) and (2, 5) is 3 + x
+    let res = lagrange_interpolation_at(
         &[BigInt::from(1), BigInt::from(2)],
         &[BigInt::from(4), BigInt::from(5)],
+        &BigInt::from(10),
         &BigInt::from(1613),
     );
-
-    assert_eq!(res1, BigInt::from(3))
+    assert_eq!(res, BigInt::from(13));
 }
 
 #[test]
-fn test_evaluate_polynomial() {
-    use num_primes::Generator;
-    let secretkey_int = BigInt::from(1234);
-    let hash_pass = BigInt::from(5);
+fn test_upgrade_password_kdf() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let (user_share, index) = authorizer.register_user_shares(&uid, "password");
+    assert_eq!(
+        authorizer.open_store(&index).unwrap().unwrap().password_kdf,
+        PasswordKdf::default()
+    );
+    let key = authorizer.get_priv_key(&uid, Some("password".to_string()), vec![]);
+    assert!(key.is_ok());
 
-    let prime_gen = Generator::new_prime(256).to_bytes_le();
-    let prime_arr: [u8; 32] = prime_gen
-        .try_into()
-        .expect("Could not turn u64 vec into bytes?");
-    let prime = BigInt::from_bytes_le(num_bigint::Sign::Plus, &prime_arr);
+    // the default PBKDF2 parameters are the ones the crate's defaults were
+    let salt = SaltString::generate(&mut OsRng);
+    let phc = Pbkdf2
+        .hash_password("password".as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+    let derive = |kdf: &PasswordKdf| kdf.derive("password", salt.as_str()).unwrap().0.clone();
+    assert_eq!(
+        derive(&PasswordKdf::default()),
+        BigInt::from_bytes_le(num_bigint::Sign::Plus, phc.as_bytes())
+    );
+    let fewer_rounds = PasswordKdf::Pbkdf2 {
+        rounds: 1_000,
+        output_len: PBKDF2_OUTPUT_LEN,
+    };
+    assert_ne!(derive(&fewer_rounds), derive(&PasswordKdf::default()));
+    assert!(!authorizer.set_password_kdf(PasswordKdf::Pbkdf2 {
+        rounds: 0,
+        output_len: PBKDF2_OUTPUT_LEN,
+    }));
+
+    let argon2id = PasswordKdf::Argon2id {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+    assert!(!authorizer.set_password_kdf(PasswordKdf::Argon2id {
+        m_cost: 0,
+        t_cost: 0,
+        p_cost: 0,
+    }));
+    assert!(authorizer.set_password_kdf(argon2id.clone()));
+
+    // a wrong password doesn't upgrade anything
+    assert!(authorizer
+        .get_priv_key(&uid, Some("wrong".to_string()), vec![])
+        .is_err());
+    assert_eq!(
+        authorizer.open_store(&index).unwrap().unwrap().password_kdf,
+        PasswordKdf::default()
+    );
+    authorizer.reset_failed_unlocks(&uid);
 
-    let ref tss = ShamirSecretSharing {
-        threshold: 1,
-        share_count: 3,
-        prime: prime,
+    // logging in upgrades the KDF, without invalidating the user's share
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("password".to_string()), vec![]),
+        key
+    );
+    assert_eq!(
+        authorizer.open_store(&index).unwrap().unwrap().password_kdf,
+        argon2id
+    );
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("password".to_string()), vec![]),
+        key
+    );
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(user_share, index)]),
+        key
+    );
+
+    // new users get the new KDF directly
+    let scrypt = PasswordKdf::Scrypt {
+        log_n: 4,
+        r: 8,
+        p: 1,
     };
-    let shares = tss.share(&secretkey_int, &hash_pass);
+    assert!(authorizer.set_password_kdf(scrypt.clone()));
+    let bob = "bob".to_string();
+    let (_, index) = authorizer.register_user_shares(&bob, "password");
+    assert_eq!(
+        authorizer.open_store(&index).unwrap().unwrap().password_kdf,
+        scrypt
+    );
+    assert!(authorizer
+        .get_priv_key(&bob, Some("password".to_string()), vec![])
+        .is_ok());
+}
+
+#[cfg(test)]
+struct TestClock(std::sync::Arc<std::sync::Mutex<SystemTime>>);
 
-    assert_eq!(tss.reconstruct(&(shares[..2].to_vec())), BigInt::from(1234));
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
 }
 
 #[test]
-fn simple_realistic_test() {
-    use num_primes::Generator;
-    use rand::rngs::OsRng;
-    let mut rng = OsRng;
+fn test_failed_unlock_lockout() {
+    use std::sync::{Arc, Mutex};
+    let now = Arc::new(Mutex::new(SystemTime::UNIX_EPOCH));
+    let advance = |secs: u64| *now.lock().unwrap() += Duration::from_secs(secs);
+
+    let mut authorizer = Authorizer::new();
+    authorizer.set_clock(Box::new(TestClock(now.clone())));
+    authorizer.set_lockout_policy(LockoutPolicy {
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(4),
+        max_failures: 5,
+        lockout_duration: Duration::from_secs(100),
+    });
+    let uid = "alice".to_string();
+    let (user_share, index) = authorizer.register_user_shares(&uid, "password");
+    let wrong = || Some("wrong".to_string());
+    let right = || Some("password".to_string());
+
+    assert_eq!(
+        authorizer.get_priv_key(&"bob".to_string(), right(), vec![]),
+        Err(AuthError::UnknownUser)
+    );
 
-    let secretkey = SecretKey::generate(&mut rng);
-    let secretkey_int = BigInt::from_bytes_le(num_bigint::Sign::Plus, secretkey.as_bytes());
+    // backoff doubles after each failure, up to the max delay
+    for delay in [1, 2, 4, 4] {
+        assert_eq!(
+            authorizer.get_priv_key(&uid, wrong(), vec![]),
+            Err(AuthError::WrongPassword)
+        );
+        assert_eq!(
+            authorizer.get_priv_key(&uid, right(), vec![]),
+            Err(AuthError::Locked {
+                retry_after: Duration::from_secs(delay)
+            })
+        );
+        advance(delay);
+    }
 
-    let hash_pass = BigInt::from(524736458);
+    // locked out after max_failures
+    assert_eq!(
+        authorizer.get_priv_key(&uid, wrong(), vec![]),
+        Err(AuthError::WrongPassword)
+    );
+    advance(99);
+    assert_eq!(
+        authorizer.get_priv_key(&uid, right(), vec![]),
+        Err(AuthError::Locked {
+            retry_after: Duration::from_secs(1)
+        })
+    );
+    advance(1);
+    let key = authorizer.get_priv_key(&uid, right(), vec![]).unwrap();
+
+    // success resets the count; a tampered share counts as a failure too
+    let mut tampered = user_share.clone();
+    tampered[1] += 1;
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(tampered, index)]),
+        Err(AuthError::TamperedShare(ShareSource::User(0)))
+    );
+    assert_eq!(
+        authorizer.get_priv_key(&uid, right(), vec![]),
+        Err(AuthError::Locked {
+            retry_after: Duration::from_secs(1)
+        })
+    );
+    advance(1);
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(user_share, index)]),
+        Ok(key)
+    );
+}
 
-    let prime_gen = Generator::new_prime(512).to_bytes_le();
-    let prime_arr: [u8; 64] = prime_gen
-        .try_into()
-        .expect("Could not turn u64 vec into bytes?");
-    let prime = BigInt::from_bytes_le(num_bigint::Sign::Plus, &prime_arr);
+#[test]
+fn test_unlock_sessions() {
+    use std::sync::{Arc, Mutex};
+    let now = Arc::new(Mutex::new(SystemTime::UNIX_EPOCH));
+    let advance = |secs: u64| *now.lock().unwrap() += Duration::from_secs(secs);
+
+    let mut authorizer = Authorizer::new();
+    authorizer.set_clock(Box::new(TestClock(now.clone())));
+    authorizer.set_session_ttl(Duration::from_secs(60));
+    let uid = "alice".to_string();
+    authorizer.register_user_shares(&uid, "password");
+    let key = authorizer
+        .get_priv_key(&uid, Some("password".to_string()), vec![])
+        .unwrap();
+
+    assert_eq!(
+        authorizer.unlock(&uid, "wrong", vec![], None),
+        Err(AuthError::WrongPassword)
+    );
+    authorizer.reset_failed_unlocks(&uid);
+    let token = authorizer.unlock(&uid, "password", vec![], None).unwrap();
+    assert_eq!(
+        authorizer.get_session_key(&token),
+        Ok((uid.clone(), key.clone()))
+    );
 
-    let sss = ShamirSecretSharing {
-        threshold: 1,
-        share_count: 3,
-        prime: prime,
-    };
+    // sessions expire after the TTL
+    advance(59);
+    assert!(authorizer.get_session_key(&token).is_ok());
+    advance(1);
+    assert_eq!(
+        authorizer.get_session_key(&token),
+        Err(AuthError::InvalidSession)
+    );
 
-    let all_shares = sss.share(&secretkey_int, &hash_pass);
+    // and can be locked explicitly
+    let token = authorizer.unlock(&uid, "password", vec![], None).unwrap();
+    let other = authorizer.unlock(&uid, "password", vec![], None).unwrap();
+    assert_ne!(token, other);
+    authorizer.lock(&token);
+    assert_eq!(
+        authorizer.get_session_key(&token),
+        Err(AuthError::InvalidSession)
+    );
+    assert!(authorizer.get_session_key(&other).is_ok());
+    authorizer.lock_user(&uid);
+    assert_eq!(
+        authorizer.get_session_key(&other),
+        Err(AuthError::InvalidSession)
+    );
+
+    // or by changing the password or recovering the account
+    let token = authorizer.unlock(&uid, "password", vec![], None).unwrap();
+    let (shares, index) = authorizer
+        .change_password(&uid, "password", "new", vec![], None)
+        .unwrap();
+    assert_eq!(
+        authorizer.get_session_key(&token),
+        Err(AuthError::InvalidSession)
+    );
+    let token = authorizer.unlock(&uid, "new", vec![], None).unwrap();
+    authorizer
+        .recover_account(&uid, vec![(shares[0].1.clone(), index)], "password", None)
+        .unwrap();
+    assert_eq!(
+        authorizer.get_session_key(&token),
+        Err(AuthError::InvalidSession)
+    );
 
-    assert_eq!(sss.reconstruct(&(all_shares[..2].to_vec())), secretkey_int);
+    // users whose config needs more than the password and the server's share
+    // unlock with their own shares too
+    let bob = "bob".to_string();
+    let config = ShareConfig::new(
+        3,
+        vec![ShareRole::Password, ShareRole::Server, ShareRole::User],
+    )
+    .unwrap();
+    let (shares, index) = authorizer.register_user_shares_with_config(&bob, "password", &config);
+    assert_eq!(
+        authorizer.unlock(&bob, "password", vec![], None),
+        Err(AuthError::NotEnoughShares)
+    );
+    let token = authorizer
+        .unlock(&bob, "password", vec![(shares[0].1.clone(), index)], None)
+        .unwrap();
+    assert_eq!(authorizer.get_session_key(&token).unwrap().0, bob);
 }
 
 #[test]
-fn realistic_test() {
-    use num_primes::Generator;
-    use pbkdf2::{
-        password_hash::{PasswordHasher, SaltString},
-        Pbkdf2,
+fn test_verifiable_shares() {
+    let prime = well_known_prime();
+    let group = CommitmentGroup::for_prime(&prime);
+    // the hardcoded cofactor is the one the search would find
+    assert_eq!(&find_cofactor(&prime) * &prime + 1, group.modulus);
+    for group in [group.clone(), CommitmentGroup::for_prime(&generate_prime())] {
+        assert_eq!(group.modulus.bits(), COMMITMENT_MODULUS_BITS);
+        assert!(is_prime(&group.modulus));
+        assert_ne!(group.generator, BigInt::from(1));
+        assert_eq!(
+            group.generator.modpow(&group.prime, &group.modulus),
+            BigInt::from(1)
+        );
+    }
+    let sss = ShamirSecretSharing {
+        threshold: 2,
+        share_count: 5,
+        prime: prime.clone(),
     };
-    use rand::rngs::OsRng;
+    let secret = BigInt::from(1234);
+    let (mut shares, commitments) = sss.share_verifiable(&secret, &BigInt::from(5), &group);
+    assert!(shares.iter().all(|s| group.verify(&commitments, s)));
+    assert_eq!(
+        sss.reconstruct_verified(&shares[1..4], &commitments, &group),
+        Ok(secret)
+    );
 
-    // --------------- construction ---------------
+    shares[2][1] += 1;
+    assert_eq!(
+        sss.reconstruct_verified(&shares[1..4], &commitments, &group),
+        Err(ShareError::CommitmentMismatch(1))
+    );
+}
 
-    let mut rng = OsRng;
+#[test]
+fn test_tampered_shares() {
+    let mut authorizer = Authorizer::new();
+    let config = ShareConfig::new(
+        3,
+        vec![ShareRole::Password, ShareRole::Server, ShareRole::User],
+    )
+    .unwrap();
+    let uid = "alice".to_string();
+    let (shares, index) = authorizer.register_user_shares_with_config(&uid, "password", &config);
+    let user_share = shares[0].1.clone();
+    let (other_shares, other_index) =
+        authorizer.register_user_shares_with_config(&"bob".to_string(), "password", &config);
+    let password = Some("password".to_string());
+    assert!(authorizer
+        .get_priv_key(&uid, password.clone(), vec![(user_share.clone(), index)])
+        .is_ok());
+
+    // another user's share is a mismatch, not tampering
+    assert_eq!(
+        authorizer.get_priv_key(
+            &uid,
+            password.clone(),
+            vec![(other_shares[0].1.clone(), other_index)]
+        ),
+        Err(AuthError::InvalidShares)
+    );
+    authorizer.reset_failed_unlocks(&uid);
 
-    let secretkey = SecretKey::generate(&mut rng);
-    let secretkey_int = BigInt::from_bytes_le(num_bigint::Sign::Plus, secretkey.as_bytes());
+    let mut forged = user_share.clone();
+    forged[1] += 1;
+    assert_eq!(
+        authorizer.get_priv_key(&uid, password.clone(), vec![(forged, index)]),
+        Err(AuthError::TamperedShare(ShareSource::User(0)))
+    );
+    authorizer.reset_failed_unlocks(&uid);
+
+    let mut store = authorizer.open_store(&index).unwrap().unwrap();
+    store.shares[0][1] += 1;
+    authorizer.seal_store(index, &store);
+    assert_eq!(
+        authorizer.get_priv_key(&uid, password, vec![(user_share, index)]),
+        Err(AuthError::TamperedShare(ShareSource::Server(0)))
+    );
+}
 
-    let password = String::from("password");
+#[test]
+fn test_refresh_shares() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let (old_share, index) = authorizer.register_user_shares(&uid, "password");
+    let key = authorizer
+        .get_priv_key(&uid, Some("password".to_string()), vec![])
+        .unwrap();
+    let backup = authorizer.open_store(&index).unwrap().unwrap().clone();
+
+    let refreshed = authorizer
+        .refresh_shares(&uid, "password", vec![(old_share.clone(), index)], None)
+        .unwrap();
+    let store = authorizer.open_store(&index).unwrap().unwrap();
+    assert_eq!(refreshed[0][0], old_share[0]);
+    assert_ne!(refreshed[0][1], old_share[1]);
+    assert_ne!(store.shares, backup.shares);
+    assert_ne!(store.share_value, backup.share_value);
+
+    // the key is unchanged, and the refreshed shares reconstruct it
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("password".to_string()), vec![]),
+        Ok(key.clone())
+    );
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(refreshed[0].clone(), index)]),
+        Ok(key.clone())
+    );
 
-    let salt = SaltString::generate(&mut rng);
-    let _pass_info: String = Pbkdf2
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
-        .to_string();
-    // let hash_pass_bigint = BigInt::from_bytes_le(num_bigint::Sign::Plus, pass_info.as_bytes());
-    let hash_pass_bigint = BigInt::from(123456789);
+    // shares from before the refresh don't
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(old_share.clone(), index)]),
+        Err(AuthError::StaleShare(ShareSource::User(0)))
+    );
+    // and a backed-up server share is useless with the refreshed user share
+    let key_int = BigInt::from_bytes_le(num_bigint::Sign::Plus, &key);
+    let sss = ShamirSecretSharing {
+        threshold: 1,
+        share_count: 2,
+        prime: well_known_prime(),
+    };
+    let current = authorizer.open_store(&index).unwrap().unwrap().shares[0].clone();
+    assert_eq!(
+        sss.reconstruct(&[current, refreshed[0].clone()]),
+        Ok(key_int.clone())
+    );
+    assert_ne!(
+        sss.reconstruct(&[backup.shares[0].clone(), refreshed[0].clone()]),
+        Ok(key_int)
+    );
+}
 
-    let prime_gen = Generator::new_prime(512).to_bytes_le();
-    let prime_arr: [u8; 64] = prime_gen
-        .try_into()
-        .expect("Could not turn u64 vec into bytes?");
-    let prime = BigInt::from_bytes_le(num_bigint::Sign::Plus, &prime_arr);
+#[test]
+fn test_recovery_share_encoding() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let (share, index) = authorizer.register_user_shares(&uid, "forgotten");
+    let key = authorizer
+        .get_priv_key(&uid, Some("forgotten".to_string()), vec![])
+        .unwrap();
+
+    let recovery = authorizer.recovery_share(&share, &index).unwrap();
+    let code = recovery.encode();
+    assert!(code.starts_with(RECOVERY_SHARE_PREFIX));
+    let wrapped = format!("{}\n  {}", &code[..30], &code[30..]);
+    let decoded = RecoveryShare::decode(&wrapped).unwrap();
+    assert_eq!(decoded, recovery);
+    let (share, index) = authorizer.check_recovery_share(&decoded).unwrap();
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(share, index)]),
+        Ok(key)
+    );
 
-    println!("prime: {}", prime.clone());
+    let legacy = RecoveryShare {
+        share: [BigInt::from(1), BigInt::from(0)],
+        index: ShareIndex::Legacy(42),
+        prime_id: [7; 8],
+    };
+    assert_eq!(RecoveryShare::decode(&legacy.encode()), Ok(legacy.clone()));
+    assert_eq!(
+        authorizer.check_recovery_share(&legacy),
+        Err(AuthError::UnknownPrime)
+    );
+
+    // typos are caught by the checksum
+    let mut typo = code.clone().into_bytes();
+    typo[20] = if typo[20] == b'A' { b'B' } else { b'A' };
+    let typo = String::from_utf8(typo).unwrap();
+    assert_eq!(
+        RecoveryShare::decode(&typo),
+        Err(RecoveryShareError::BadChecksum)
+    );
+    assert_eq!(
+        RecoveryShare::decode(&code[RECOVERY_SHARE_PREFIX.len()..]),
+        Err(RecoveryShareError::MissingPrefix)
+    );
+    assert_eq!(
+        RecoveryShare::decode(&format!("{}!!", RECOVERY_SHARE_PREFIX)),
+        Err(RecoveryShareError::BadEncoding)
+    );
+    let mut bytes = vec![2u8];
+    bytes.extend_from_slice(&Sha256::digest(&bytes)[..4]);
+    let future = format!(
+        "{}{}",
+        RECOVERY_SHARE_PREFIX,
+        base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+    );
+    assert_eq!(
+        RecoveryShare::decode(&future),
+        Err(RecoveryShareError::UnsupportedVersion(2))
+    );
+}
+
+#[test]
+fn test_byte_secret_sharing() {
+    let bss = ByteSecretSharing {
+        threshold: 2,
+        share_count: 5,
+        prime: well_known_prime(),
+    };
+    assert_eq!(bss.chunk_len(), 64);
+    // several keys' worth, with leading and trailing zeros
+    let mut secret = vec![0u8; 3];
+    secret.extend((0..150).map(|i| i as u8));
+    secret.extend([0u8; 3]);
+    let secret = Zeroizing::new(secret);
+    let shares = bss.share(&secret, &BigInt::from(5));
+    assert_eq!(shares.len(), 5);
+    assert_eq!(shares[0].0, BigInt::from(5));
+    assert!(shares.iter().all(|(_, ys)| ys.len() == 3));
+    assert_eq!(bss.reconstruct(&shares[..3]), Ok(secret.clone()));
+    assert_eq!(bss.reconstruct(&shares[2..]), Ok(secret.clone()));
+    assert_eq!(bss.reconstruct(&shares[..2]), Err(ShareError::TooFewShares));
+
+    // a wrong share is almost always caught
+    let mut wrong = shares[..3].to_vec();
+    wrong[1].1[0] += 1;
+    assert_eq!(bss.reconstruct(&wrong), Err(ShareError::InvalidChunk));
+    wrong[1].1.pop();
+    assert_eq!(bss.reconstruct(&wrong), Err(ShareError::LengthMismatch));
+
+    // chunks shrink with the prime
+    let small = ByteSecretSharing {
+        threshold: 1,
+        share_count: 2,
+        prime: (BigInt::from(1) << 127) - 1,
+    };
+    assert_eq!(small.chunk_len(), 14);
+    let shares = small.share(&secret, &BigInt::from(5));
+    assert_eq!(shares[0].1.len(), 12);
+    assert_eq!(small.reconstruct(&shares), Ok(secret));
+    let shares = small.share(&[], &BigInt::from(5));
+    assert_eq!(small.reconstruct(&shares), Ok(Zeroizing::new(vec![])));
+    assert_eq!(
+        small.reconstruct(&shares[..1]),
+        Err(ShareError::TooFewShares)
+    );
+}
 
+#[test]
+fn test_keys_round_trip() {
+    let prime = well_known_prime();
     let sss = ShamirSecretSharing {
         threshold: 1,
         share_count: 3,
         prime: prime.clone(),
     };
+    let mut keys = vec![[0u8; 32], [0xff; 32]];
+    // random keys with every number of trailing zero bytes
+    for zeros in 0..=32 {
+        for _ in 0..4 {
+            let mut key = [0u8; 32];
+            OsRng.fill_bytes(&mut key[..32 - zeros]);
+            keys.push(key);
+        }
+    }
+    for key in keys {
+        let value = key_to_bigint(&key);
+        assert_eq!(bigint_to_key(&value), Some(key));
+        let shares = sss.share(&value, &OsRng.gen_bigint_range(&BigInt::from(1), &prime));
+        let reconstructed = sss.reconstruct(&shares[1..]).unwrap();
+        assert_eq!(bigint_to_key(&reconstructed), Some(key));
+    }
 
-    let all_shares = sss.share(&secretkey_int, &hash_pass_bigint);
+    // values that can't be keys are rejected rather than truncated or padded
+    assert_eq!(bigint_to_key(&(BigInt::from(1) << 256)), None);
+    assert_eq!(bigint_to_key(&BigInt::from(-1)), None);
+    assert_eq!(get_pk_bytes(&[1; 31]), None);
+    assert_eq!(get_pk_bytes(&[1; 33]), None);
+    assert_eq!(get_pk_bytes(&[1; 32]), Some([1; 32]));
+}
 
-    // --------------- reconstruction ---------------
+#[test]
+fn test_zeroize_secrets() {
+    let mut secret = SecretInt(well_known_prime() - 1);
+    secret.zeroize();
+    assert_eq!(*secret, BigInt::from(0));
+
+    // keys are handed out in wrappers that wipe them when dropped
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    authorizer.register_user_shares(&uid, "password");
+    let key: Zeroizing<Vec<u8>> = authorizer
+        .get_priv_key(&uid, Some("password".to_string()), vec![])
+        .unwrap();
+    assert_eq!(key.len(), 32);
+}
 
-    let mut recon_shares: Vec<[BigInt; 2]> = vec![];
+#[test]
+fn test_key_rotation() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let (old_share, index) = authorizer.register_user_shares(&uid, "password");
+    let password = || Some("password".to_string());
+    let old_key = authorizer.get_priv_key(&uid, password(), vec![]).unwrap();
+    let token = authorizer.unlock(&uid, "password", vec![], None).unwrap();
+    let record = encrypt_with_pubkey(&authorizer.get_pubkey(&uid).unwrap(), &b"data".to_vec());
+
+    // nothing changes until the rotation is finished
+    let rotation = authorizer
+        .start_key_rotation(&uid, "password", vec![], None)
+        .unwrap();
+    assert_eq!(rotation.old_key, old_key);
+    assert_eq!(
+        authorizer.get_priv_key(&uid, password(), vec![]),
+        Ok(old_key.clone())
+    );
 
-    let edna_share = all_shares[1].clone();
-    let edna_share_value = all_shares[0][1].clone();
+    let (_, plaintext) = decrypt_encdata(&record, &rotation.old_key);
+    let record = encrypt_with_pubkey(&rotation.new_pubkey, &plaintext);
+    let new_pubkey = rotation.new_pubkey.clone();
+    let (new_shares, new_index) = authorizer.finish_key_rotation(rotation).unwrap();
+    assert_eq!(new_index, index);
+    assert_eq!(authorizer.get_pubkey(&uid), Some(new_pubkey));
+
+    let new_key = authorizer.get_priv_key(&uid, password(), vec![]).unwrap();
+    assert_ne!(new_key, old_key);
+    assert_eq!(decrypt_encdata(&record, &new_key), (true, b"data".to_vec()));
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(new_shares[0].1.clone(), new_index)]),
+        Ok(new_key)
+    );
 
-    let _pass_info2: String = Pbkdf2
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
-        .to_string();
-    // let hash_pass_bigint2 = BigInt::from_bytes_le(num_bigint::Sign::Plus, pass_info2.as_bytes());
-    let hash_pass_bigint2 = BigInt::from(123456789);
+    // the old shares and sessions no longer work
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(old_share, index)]),
+        Err(AuthError::StaleShare(ShareSource::User(0)))
+    );
+    assert_eq!(
+        authorizer.get_session_key(&token),
+        Err(AuthError::InvalidSession)
+    );
+}
 
-    let derived_share = [hash_pass_bigint2, edna_share_value.clone()];
+#[test]
+fn test_social_recovery() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let contacts: Vec<UID> = vec!["bob".into(), "carol".into(), "dave".into()];
+    authorizer.register_user_shares(&uid, "forgotten");
+    for contact in &contacts {
+        authorizer.register_user_shares(contact, contact);
+    }
+    let key = authorizer
+        .get_priv_key(&uid, Some("forgotten".to_string()), vec![])
+        .unwrap();
 
-    recon_shares.push(edna_share);
-    recon_shares.push(derived_share);
+    assert_eq!(
+        authorizer.set_trusted_contacts(&uid, "forgotten", &contacts, 4, None),
+        Err(AuthError::InvalidThreshold)
+    );
+    assert_eq!(
+        authorizer.set_trusted_contacts(
+            &uid,
+            "forgotten",
+            &[uid.clone(), contacts[0].clone()],
+            2,
+            None
+        ),
+        Err(AuthError::InvalidContact(uid.clone()))
+    );
+    let twice = [
+        contacts[0].clone(),
+        contacts[0].clone(),
+        contacts[1].clone(),
+    ];
+    assert_eq!(
+        authorizer.set_trusted_contacts(&uid, "forgotten", &twice, 2, None),
+        Err(AuthError::InvalidContact(contacts[0].clone()))
+    );
+    assert_eq!(
+        authorizer.set_trusted_contacts(
+            &uid,
+            "forgotten",
+            &[contacts[0].clone(), "eve".into()],
+            2,
+            None
+        ),
+        Err(AuthError::UnknownUser)
+    );
+    authorizer
+        .set_trusted_contacts(&uid, "forgotten", &contacts, 2, None)
+        .unwrap();
+    assert_eq!(
+        authorizer.get_contact_owners(&contacts[0]),
+        vec![uid.clone()]
+    );
+    assert!(authorizer.get_contact_owners(&uid).is_empty());
+
+    // each contact decrypts their share with their own key
+    let shares: Vec<Share> = contacts
+        .iter()
+        .map(|contact| {
+            authorizer
+                .decrypt_contact_share(contact, contact, &uid, None)
+                .unwrap()
+        })
+        .collect();
+    assert!(authorizer
+        .decrypt_contact_share(&contacts[0], &contacts[0], &contacts[1], None)
+        .is_err());
+
+    assert_eq!(
+        authorizer.recover_with_contacts(&uid, shares[..1].to_vec(), "new"),
+        Err(AuthError::NotEnoughShares)
+    );
+    let mut tampered = shares[..2].to_vec();
+    tampered[0][1] += 1;
+    assert_eq!(
+        authorizer.recover_with_contacts(&uid, tampered, "new"),
+        Err(AuthError::InvalidShares)
+    );
 
-    println!("share 0: {}, {}", recon_shares[0][0], recon_shares[0][1]);
-    println!("share 1: {}, {}", recon_shares[1][0], recon_shares[1][1]);
+    authorizer
+        .recover_with_contacts(&uid, shares[1..].to_vec(), "new")
+        .unwrap();
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("new".to_string()), vec![]),
+        Ok(key)
+    );
+    assert!(authorizer
+        .get_priv_key(&uid, Some("forgotten".to_string()), vec![])
+        .is_err());
+}
 
-    // --------------- assert equal ---------------
+#[test]
+fn test_contact_key_rotation() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let contacts: Vec<UID> = vec!["bob".into(), "carol".into()];
+    authorizer.register_user_shares(&uid, "forgotten");
+    for contact in &contacts {
+        authorizer.register_user_shares(contact, contact);
+    }
+    let key = authorizer
+        .get_priv_key(&uid, Some("forgotten".to_string()), vec![])
+        .unwrap();
+    authorizer
+        .set_trusted_contacts(&uid, "forgotten", &contacts, 2, None)
+        .unwrap();
+
+    // a contact who rotates their key can still decrypt the share they hold
+    let rotation = authorizer
+        .start_key_rotation(&contacts[0], &contacts[0], vec![], None)
+        .unwrap();
+    authorizer.finish_key_rotation(rotation).unwrap();
+    let shares: Vec<Share> = contacts
+        .iter()
+        .map(|contact| {
+            authorizer
+                .decrypt_contact_share(contact, contact, &uid, None)
+                .unwrap()
+        })
+        .collect();
+    authorizer
+        .recover_with_contacts(&uid, shares, "new")
+        .unwrap();
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("new".to_string()), vec![]),
+        Ok(key)
+    );
+}
 
-    println!("checking fake reconstruction");
-    let alleged_priv_key = sss.reconstruct(&(all_shares[..2].to_vec()));
-    println!("private key: {}", secretkey_int);
-    println!("found private key: {}", alleged_priv_key);
-    assert!(secretkey_int.eq(&alleged_priv_key));
+#[test]
+fn test_totp() {
+    use std::sync::{Arc, Mutex};
+    // RFC 6238's SHA-1 test vectors, truncated to 6 digits
+    let rfc_seed = b"12345678901234567890";
+    for (secs, code) in [(59, 287082), (1111111109, 81804), (2000000000, 279037)] {
+        let time = UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(totp_code(rfc_seed, totp_step(time)), code);
+    }
 
-    let recon_priv_key = sss.reconstruct(&recon_shares);
-    println!("checking actual reconstruction");
-    println!("private key: {}", secretkey_int);
-    println!("found private key: {}", recon_priv_key);
-    assert!(secretkey_int.eq(&recon_priv_key));
+    let now = Arc::new(Mutex::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+    let advance = |secs: u64| *now.lock().unwrap() += Duration::from_secs(secs);
+    let step = || totp_step(*now.lock().unwrap());
+    let mut authorizer = Authorizer::new();
+    authorizer.set_clock(Box::new(TestClock(now.clone())));
+    let uid = "alice".to_string();
+    let password = || Some("password".to_string());
+    let (user_share, index) = authorizer.register_user_shares(&uid, "password");
+    let key = authorizer.get_priv_key(&uid, password(), vec![]).unwrap();
+
+    // enrolling takes effect once the user shows they saved the seed
+    let seed = authorizer.enroll_totp(&uid, "password", None).unwrap();
+    assert_eq!(
+        authorizer.get_priv_key(&uid, password(), vec![]),
+        Ok(key.clone())
+    );
+    let wrong = (totp_code(&seed, step()) + 1) % 1_000_000;
+    assert_eq!(
+        authorizer.confirm_totp(&uid, wrong),
+        Err(AuthError::WrongCode)
+    );
+    authorizer.reset_failed_unlocks(&uid);
+    authorizer
+        .confirm_totp(&uid, totp_code(&seed, step()))
+        .unwrap();
+    assert_eq!(
+        authorizer.get_priv_key(&uid, password(), vec![]),
+        Err(AuthError::TotpRequired)
+    );
+    // the server's share isn't needed with the user's
+    assert_eq!(
+        authorizer.get_priv_key(&uid, password(), vec![(user_share, index)]),
+        Ok(key.clone())
+    );
+
+    advance(30);
+    let code = totp_code(&seed, step());
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(&uid, password(), vec![], code),
+        Ok(key.clone())
+    );
+    // codes can't be replayed
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(&uid, password(), vec![], code),
+        Err(AuthError::WrongCode)
+    );
+
+    // a code one step off is accepted with the default skew, but not two
+    advance(60);
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(&uid, password(), vec![], totp_code(&seed, step() - 2)),
+        Err(AuthError::WrongCode)
+    );
+    advance(60);
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(&uid, password(), vec![], totp_code(&seed, step() - 1)),
+        Ok(key.clone())
+    );
+    authorizer.set_totp_skew(0);
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(&uid, password(), vec![], totp_code(&seed, step() + 1)),
+        Err(AuthError::WrongCode)
+    );
+
+    // re-enrolling needs a code too, and the old seed works until the new one
+    // is confirmed
+    advance(60);
+    assert_eq!(
+        authorizer.enroll_totp(&uid, "password", None),
+        Err(AuthError::TotpRequired)
+    );
+    let new_seed = authorizer
+        .enroll_totp(&uid, "password", Some(totp_code(&seed, step())))
+        .unwrap();
+    advance(30);
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(&uid, password(), vec![], totp_code(&seed, step())),
+        Ok(key.clone())
+    );
+    authorizer
+        .confirm_totp(&uid, totp_code(&new_seed, step()))
+        .unwrap();
+    advance(30);
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(&uid, password(), vec![], totp_code(&seed, step())),
+        Err(AuthError::WrongCode)
+    );
+    authorizer.reset_failed_unlocks(&uid);
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(&uid, password(), vec![], totp_code(&new_seed, step())),
+        Ok(key)
+    );
+    assert_eq!(
+        authorizer.confirm_totp(&uid, totp_code(&new_seed, step())),
+        Err(AuthError::WrongCode)
+    );
+}
+
+#[test]
+fn test_totp_operations() {
+    use std::sync::{Arc, Mutex};
+    let now = Arc::new(Mutex::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+    let advance = |secs: u64| *now.lock().unwrap() += Duration::from_secs(secs);
+    let step = || totp_step(*now.lock().unwrap());
+    let mut authorizer = Authorizer::new();
+    authorizer.set_clock(Box::new(TestClock(now.clone())));
+    let uid = "alice".to_string();
+    authorizer.register_user_shares(&uid, "password");
+    let key = authorizer
+        .get_priv_key(&uid, Some("password".to_string()), vec![])
+        .unwrap();
+    let seed = authorizer.enroll_totp(&uid, "password", None).unwrap();
+    authorizer
+        .confirm_totp(&uid, totp_code(&seed, step()))
+        .unwrap();
+
+    // operations that need the server's shares take the code too
+    advance(30);
+    assert_eq!(
+        authorizer.unlock(&uid, "password", vec![], None),
+        Err(AuthError::TotpRequired)
+    );
+    let token = authorizer
+        .unlock(&uid, "password", vec![], Some(totp_code(&seed, step())))
+        .unwrap();
+    assert_eq!(
+        authorizer.get_session_key(&token),
+        Ok((uid.clone(), key.clone()))
+    );
+
+    advance(30);
+    assert_eq!(
+        authorizer.change_password(&uid, "password", "new", vec![], None),
+        Err(AuthError::TotpRequired)
+    );
+    authorizer
+        .change_password(
+            &uid,
+            "password",
+            "new",
+            vec![],
+            Some(totp_code(&seed, step())),
+        )
+        .unwrap();
+    advance(30);
+    assert_eq!(
+        authorizer.get_priv_key_with_totp(
+            &uid,
+            Some("new".to_string()),
+            vec![],
+            totp_code(&seed, step())
+        ),
+        Ok(key)
+    );
+}
+
+#[test]
+fn test_master_key() {
+    let mut authorizer = Authorizer::new();
+    let uid = "alice".to_string();
+    let (user_share, index) = authorizer.register_user_shares(&uid, "password");
+    let key = authorizer
+        .get_priv_key(&uid, Some("password".to_string()), vec![])
+        .unwrap();
+
+    // master keys load from keyfiles and the environment
+    let master_key = MasterKey::generate();
+    let keyfile = std::env::temp_dir().join(format!("hydra-master-key-{}", OsRng.next_u64()));
+    std::fs::write(&keyfile, format!("{}\n", *master_key.to_base64())).unwrap();
+    assert_eq!(
+        MasterKey::from_keyfile(&keyfile).unwrap().id(),
+        master_key.id()
+    );
+    std::fs::write(&keyfile, "not a key").unwrap();
+    assert!(MasterKey::from_keyfile(&keyfile).is_err());
+    std::fs::remove_file(&keyfile).unwrap();
+    std::env::set_var(MASTER_KEY_ENV, &*master_key.to_base64());
+    assert_eq!(MasterKey::from_env().unwrap().id(), master_key.id());
+    std::env::remove_var(MASTER_KEY_ENV);
+    assert_eq!(
+        MasterKey::from_env().map_err(|e| e.kind()).err(),
+        Some(io::ErrorKind::NotFound)
+    );
+    assert!(MasterKey::from_base64(&base64::encode([0u8; 31])).is_none());
+
+    // load reads either, preferring the key itself
+    std::fs::write(&keyfile, format!("{}\n", *master_key.to_base64())).unwrap();
+    std::env::set_var(MASTER_KEYFILE_ENV, &keyfile);
+    assert_eq!(MasterKey::load().unwrap().id(), master_key.id());
+    let other_key = MasterKey::generate();
+    std::env::set_var(MASTER_KEY_ENV, &*other_key.to_base64());
+    assert_eq!(MasterKey::load().unwrap().id(), other_key.id());
+    std::env::set_var(MASTER_KEY_ENV, base64::encode([0u8; 31]));
+    assert_eq!(
+        MasterKey::load().map_err(|e| e.kind()).err(),
+        Some(io::ErrorKind::InvalidData)
+    );
+    std::env::remove_var(MASTER_KEY_ENV);
+    std::env::remove_var(MASTER_KEYFILE_ENV);
+    std::fs::remove_file(&keyfile).unwrap();
+    assert_eq!(
+        MasterKey::load().map_err(|e| e.kind()).err(),
+        Some(io::ErrorKind::NotFound)
+    );
+
+    // an authorizer made with the master key opens stores sealed under it
+    let mut restarted = Authorizer::new_with_keys(b"server secret", master_key.clone());
+    assert_eq!(restarted.master_key_id(), master_key.id());
+    let (_, restarted_index) = restarted.register_user_shares(&uid, "password");
+    assert_eq!(
+        restarted.shares_map[&restarted_index].master_key_id,
+        master_key.id()
+    );
+
+    // stores are only readable with the master key they were sealed under
+    let dump = authorizer.shares_map.clone();
+    assert_ne!(dump[&index].master_key_id, master_key.id());
+    authorizer.rotate_master_key(master_key.clone()).unwrap();
+    assert_eq!(authorizer.master_key_id(), master_key.id());
+    assert!(authorizer
+        .shares_map
+        .values()
+        .all(|sealed| sealed.master_key_id == master_key.id()));
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("password".to_string()), vec![]),
+        Ok(key.clone())
+    );
+    assert_eq!(
+        authorizer.get_priv_key(&uid, None, vec![(user_share, index)]),
+        Ok(key)
+    );
+
+    authorizer.shares_map = dump;
+    assert_eq!(
+        authorizer.get_priv_key(&uid, Some("password".to_string()), vec![]),
+        Err(AuthError::WrongMasterKey)
+    );
+    assert_eq!(
+        authorizer.rotate_master_key(MasterKey::generate()),
+        Err(AuthError::WrongMasterKey)
+    );
+    assert_eq!(authorizer.master_key_id(), master_key.id());
+}
+
+#[test]
+fn test_load_locator_key() {
+    let key = [7u8; LOCATOR_KEY_LEN];
+    let keyfile = std::env::temp_dir().join(format!("hydra-locator-key-{}", OsRng.next_u64()));
+    std::fs::write(&keyfile, format!("{}\n", base64::encode(key))).unwrap();
+    std::env::set_var(LOCATOR_KEYFILE_ENV, &keyfile);
+    assert_eq!(*load_locator_key().unwrap(), key.to_vec());
+
+    // the key itself takes precedence over the keyfile
+    std::env::set_var(LOCATOR_KEY_ENV, base64::encode([8u8; LOCATOR_KEY_LEN]));
+    assert_eq!(*load_locator_key().unwrap(), vec![8u8; LOCATOR_KEY_LEN]);
+    std::env::set_var(LOCATOR_KEY_ENV, base64::encode([8u8; 16]));
+    assert_eq!(
+        load_locator_key().map_err(|e| e.kind()).err(),
+        Some(io::ErrorKind::InvalidData)
+    );
+
+    std::env::remove_var(LOCATOR_KEY_ENV);
+    std::env::remove_var(LOCATOR_KEYFILE_ENV);
+    std::fs::remove_file(&keyfile).unwrap();
+    assert_eq!(
+        load_locator_key().map_err(|e| e.kind()).err(),
+        Some(io::ErrorKind::NotFound)
+    );
 }
@@ -2,15 +2,11 @@ use crypto_box::aead::generic_array::GenericArray;
 use crypto_box::{aead::Aead, Box, PublicKey, SecretKey};
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
+use zeroize::Zeroizing;
 
-pub fn get_pk_bytes(bytes: Vec<u8>) -> [u8; 32] {
-    let mut bytes_vec = bytes.clone();
-    if bytes.len() != 32 {
-        let diff = 32 - bytes.len();
-        let mut add = vec![0; diff];
-        bytes_vec.append(&mut add);
-    }
-    bytes_vec.try_into().unwrap()
+// keys must be exactly 32 bytes
+pub fn get_pk_bytes(bytes: &[u8]) -> Option<[u8; 32]> {
+    bytes.try_into().ok()
 }
 /*
  * ENCRYPTION STUFF
@@ -27,8 +23,12 @@ pub fn decrypt_encdata(ed: &EncData, decrypt_cap: &Vec<u8>) -> (bool, Vec<u8>) {
     }
 
     //let start = time::Instant::now();
-    let secretkey = SecretKey::from(get_pk_bytes(decrypt_cap.clone()));
-    let pubkey = PublicKey::from(get_pk_bytes(ed.pubkey.clone()));
+    // SecretKey wipes itself when dropped, but not the bytes it's made from
+    let secretkey_bytes = get_pk_bytes(decrypt_cap).map(Zeroizing::new);
+    let (secretkey, pubkey) = match (secretkey_bytes, get_pk_bytes(&ed.pubkey)) {
+        (Some(secretkey), Some(pubkey)) => (SecretKey::from(*secretkey), PublicKey::from(pubkey)),
+        _ => return (false, vec![]),
+    };
     let salsabox = Box::new(&pubkey, &secretkey);
     /*debug!(
         "decrypt {:?} with secret {} and pubkey {}",
@@ -5,6 +5,8 @@ use std::io;
 use std::str::FromStr;
 
 pub const NULLSTR: &'static str = "NULL";
+// ER_DUP_ENTRY: a UNIQUE or PRIMARY KEY constraint was violated
+pub const ER_DUP_ENTRY: u16 = 1062;
 
 pub fn get_msql_srv_coltype(t: &mysql::consts::ColumnType) -> msql_srv::ColumnType {
     use msql_srv::ColumnType;
@@ -132,6 +134,10 @@ pub fn query_drop_txn(q: String, txn: &mut mysql::Transaction) -> mysql::Result<
     txn.query_drop(q)
 }
 
+pub fn is_dup_entry_error(e: &mysql::Error) -> bool {
+    matches!(e, mysql::Error::MySqlError(me) if me.code == ER_DUP_ENTRY)
+}
+
 pub fn escape_quotes_mysql(s: &str) -> String {
     let mut s = s.replace("\'", "\'\'");
     s = s.replace("\"", "\"\"");
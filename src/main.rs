@@ -17,5 +17,6 @@ fn init_logger() {
 fn main() {
     init_logger();
     let dbname = "pseudotester";
-    let hydra = Hydra::new("root", "pass", "127.0.0.1", dbname, false);
+    let mut hydra = Hydra::new("root", "pass", "127.0.0.1", dbname, false).unwrap();
+    hydra.setup_schema().unwrap();
 }
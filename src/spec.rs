@@ -1,15 +1,26 @@
+use crate::helpers::*;
 use fast_paths::*;
-use log::info;
+use log::{info, warn};
 use mysql::prelude::*;
+use mysql::TxOpts;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::io;
 
 pub type TableName = String;
 pub type ColName = String;
 pub type UID = String;
+pub type ObjectID = String;
+// values generated so far while creating a single object
+pub type GeneratedValues = HashMap<(TableName, ColName), mysql::Value>;
+
+// how many times to regenerate random values after a duplicate-key error
+pub const MAX_INSERT_RETRIES: usize = 10;
+// max number of rows per multi-row INSERT
+pub const INSERT_BATCH_SIZE: usize = 1000;
 
 /*
  * How to generate new object types
@@ -63,16 +74,23 @@ pub enum ValueSpec {
     ConstDate { year: u16, month: u8, day: u8 },
     Bool(bool),
     Null,
+    // generated by the database: omitted from the INSERT, and read back
+    // using LAST_INSERT_ID() if this is the object's ID column
+    AutoIncrement,
+    // a value generated earlier for another table of the same object,
+    // e.g., profiles.user_id referring to users.id
+    ColRef { table: TableName, col: ColName },
 }
 
 /*
  * General spec, including
- * - how to generate users,
+ * - how to generate users and other named objects,
  * - what tables and table links exist,
  * - metadata to calculate paths between table nodes.
  */
 pub struct Spec {
     pub user_spec: ObjectSpec,
+    pub object_specs: HashMap<String, ObjectSpec>,
     pub tables: Vec<TableName>,
     pub link2fks: HashMap<(TableName, TableName), Link>,
 
@@ -105,6 +123,7 @@ impl Spec {
         Spec {
             tables: tables.clone(),
             user_spec: user_spec,
+            object_specs: HashMap::new(),
             tab2ix: tab2ix,
             link2fks: link2fks,
             path_calculator: path_calc,
@@ -112,15 +131,43 @@ impl Spec {
         }
     }
 
-    pub fn create_user<Q: Queryable>(&self, db: &mut Q) -> mysql::Result<UID> {
-        let mut ret = String::new();
-        for ts in &self.user_spec.tables {
-            match ts.insert_row(db, Some(&self.user_spec.id))? {
-                Some(uid) => ret = uid,
-                None => (),
-            }
+    // registers a named kind of object (e.g., a "[deleted]" placeholder comment)
+    // that can later be generated with create_object
+    pub fn register_object_spec(&mut self, name: &str, object_spec: ObjectSpec) {
+        self.object_specs.insert(name.to_string(), object_spec);
+    }
+
+    pub fn create_user(&self, db: &mut mysql::PooledConn) -> mysql::Result<UID> {
+        let mut uids = self.create_users(db, 1)?;
+        Ok(uids.pop().unwrap_or_default())
+    }
+
+    // creates n users at once, using multi-row INSERTs for each table;
+    // returns the UIDs in the order the users were generated
+    pub fn create_users(&self, db: &mut mysql::PooledConn, n: usize) -> mysql::Result<Vec<UID>> {
+        create_objects_from_spec(db, &self.user_spec, n)
+    }
+
+    // creates an object of a kind registered with register_object_spec,
+    // returning the new object's ID
+    pub fn create_object(&self, db: &mut mysql::PooledConn, name: &str) -> mysql::Result<ObjectID> {
+        let mut ids = self.create_objects(db, name, 1)?;
+        Ok(ids.pop().unwrap_or_default())
+    }
+
+    pub fn create_objects(
+        &self,
+        db: &mut mysql::PooledConn,
+        name: &str,
+        n: usize,
+    ) -> mysql::Result<Vec<ObjectID>> {
+        match self.object_specs.get(name) {
+            Some(object_spec) => create_objects_from_spec(db, object_spec, n),
+            None => Err(mysql::Error::IoError(io::Error::other(format!(
+                "no object spec registered for {}",
+                name
+            )))),
         }
-        Ok(ret)
     }
 
     // form a SELECT query given particular filters, that may not be filters on the target table itself
@@ -185,44 +232,228 @@ impl Spec {
     }
 }
 
+// inserts all of the objects' rows in a single transaction, so that a failed
+// insert doesn't leave behind a partially-created object
+fn create_objects_from_spec(
+    db: &mut mysql::PooledConn,
+    object_spec: &ObjectSpec,
+    n: usize,
+) -> mysql::Result<Vec<ObjectID>> {
+    let mut txn = db.start_transaction(TxOpts::default())?;
+    let mut generated = vec![GeneratedValues::new(); n];
+    for ts in &object_spec.tables {
+        ts.insert_rows(&mut txn, &mut generated)?;
+    }
+    // checked before committing, so that objects without IDs aren't kept
+    let mut ids = vec![];
+    for g in &generated {
+        match g.get(&object_spec.id) {
+            Some(id) => ids.push(mysql_val_to_string(id)),
+            None => {
+                let (table, col) = &object_spec.id;
+                return Err(mysql::Error::IoError(io::Error::other(format!(
+                    "the ID column {}.{} is not generated by any table",
+                    table, col
+                ))));
+            }
+        }
+    }
+    txn.commit()?;
+    Ok(ids)
+}
+
 impl TableSpec {
+    // `values[i]` is how to generate the value of `columns[i]`
+    pub fn new(table: &str, columns: Vec<ColName>, values: Vec<ValueSpec>) -> TableSpec {
+        assert_eq!(columns.len(), values.len());
+        TableSpec {
+            table: table.to_string(),
+            columns,
+            values,
+        }
+    }
+
+    // inserts a new row, recording the values it used in `generated` so that
+    // tables inserted later for the same object can refer to them
     pub fn insert_row<Q: Queryable>(
         &self,
         db: &mut Q,
-        return_id: Option<&(TableName, ColName)>,
-    ) -> mysql::Result<Option<UID>> {
-        let values: Vec<String> = self
-            .values
-            .iter()
-            .map(|v| valuespec2value(v).as_sql(false))
-            .collect();
+        generated: &mut GeneratedValues,
+    ) -> mysql::Result<()> {
+        self.insert_rows(db, std::slice::from_mut(generated))
+    }
 
-        let mut ret = None;
-        if let Some((tab, col)) = return_id {
-            if &self.table == tab {
-                for (i, c) in self.columns.iter().enumerate() {
-                    if c == col {
-                        ret = Some(values[i].clone());
-                        break;
+    // inserts one row per object being generated, in batches of multi-row INSERTs.
+    // A batch that hits a duplicate key is inserted again one row at a time, with
+    // the same values, so that only the colliding rows' random values are
+    // regenerated.
+    //
+    // AUTO_INCREMENT IDs are read back with one LAST_INSERT_ID() per batch, which
+    // relies on the rows of a multi-row INSERT getting consecutive IDs. MySQL only
+    // guarantees that with innodb_autoinc_lock_mode <= 1; with the default (2),
+    // tables with AUTO_INCREMENT columns are inserted one row at a time.
+    pub fn insert_rows<Q: Queryable>(
+        &self,
+        db: &mut Q,
+        generated: &mut [GeneratedValues],
+    ) -> mysql::Result<()> {
+        let mut batch_size = INSERT_BATCH_SIZE;
+        let mut autoinc_increment = 1;
+        if self.has_auto_increment() {
+            // if the settings can't be read, assume IDs may not be consecutive
+            let settings = db
+                .query_first::<(u64, u64), _>(
+                    "SELECT @@innodb_autoinc_lock_mode, @@auto_increment_increment",
+                )
+                .unwrap_or(None);
+            match settings {
+                Some((lock_mode, increment)) if lock_mode <= 1 => autoinc_increment = increment,
+                _ => batch_size = 1,
+            }
+        }
+        for batch in generated.chunks_mut(batch_size) {
+            let mut rows: Vec<Vec<mysql::Value>> = vec![];
+            for g in batch.iter() {
+                rows.push(self.generate_values(g)?);
+            }
+            self.insert_batch(db, batch, rows, autoinc_increment)?;
+        }
+        Ok(())
+    }
+
+    // `rows` holds the values generated for each of the objects in `generated`
+    fn insert_batch<Q: Queryable>(
+        &self,
+        db: &mut Q,
+        generated: &mut [GeneratedValues],
+        mut rows: Vec<Vec<mysql::Value>>,
+        autoinc_increment: u64,
+    ) -> mysql::Result<()> {
+        // random values may collide on UNIQUE columns: regenerate only the
+        // random values and retry until we run out of attempts
+        let mut attempts = 0;
+        loop {
+            match db.query_drop(self.insert_sql(&rows)) {
+                Ok(()) => break,
+                Err(e) if is_dup_entry_error(&e) && rows.len() > 1 => {
+                    warn!(
+                        "Duplicate entry inserting {} rows into {}, inserting one at a time: {}",
+                        rows.len(),
+                        self.table,
+                        e
+                    );
+                    return rows.into_iter().zip(generated.chunks_mut(1)).try_for_each(
+                        |(row, g)| self.insert_batch(db, g, vec![row], autoinc_increment),
+                    );
+                }
+                Err(e) if is_dup_entry_error(&e) && attempts < MAX_INSERT_RETRIES => {
+                    attempts += 1;
+                    warn!(
+                        "Duplicate entry inserting into {}, retry {}: {}",
+                        self.table, attempts, e
+                    );
+                    for (values, g) in rows.iter_mut().zip(generated.iter()) {
+                        for (i, v) in self.values.iter().enumerate() {
+                            if v.is_random() {
+                                values[i] = valuespec2value(v, g).unwrap();
+                            }
+                        }
                     }
                 }
+                Err(e) => return Err(e),
             }
         }
-        let q = format!(
-            "INSERT INTO {} ({}) VALUES ({})",
+
+        // the first ID the INSERT generated; the rest follow it
+        let first_id = match self.has_auto_increment() {
+            true => db.query_first::<u64, _>("SELECT LAST_INSERT_ID()")?,
+            false => None,
+        };
+        for (n, (values, g)) in rows.iter_mut().zip(generated.iter_mut()).enumerate() {
+            for (i, c) in self.columns.iter().enumerate() {
+                if let (ValueSpec::AutoIncrement, Some(id)) = (&self.values[i], first_id) {
+                    values[i] = mysql::Value::UInt(id + n as u64 * autoinc_increment);
+                }
+                g.insert((self.table.clone(), c.clone()), values[i].clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn has_auto_increment(&self) -> bool {
+        self.values
+            .iter()
+            .any(|v| matches!(v, ValueSpec::AutoIncrement))
+    }
+
+    // a multi-row INSERT of the rows, leaving out AUTO_INCREMENT columns
+    fn insert_sql(&self, rows: &[Vec<mysql::Value>]) -> String {
+        let insert_ixs: Vec<usize> = (0..self.values.len())
+            .filter(|i| !matches!(self.values[*i], ValueSpec::AutoIncrement))
+            .collect();
+        let insert_cols: Vec<&str> = insert_ixs
+            .iter()
+            .map(|i| self.columns[*i].as_str())
+            .collect();
+        let insert_rows: Vec<String> = rows
+            .iter()
+            .map(|values| {
+                let vals: Vec<String> = insert_ixs
+                    .iter()
+                    .map(|i| values[*i].as_sql(false))
+                    .collect();
+                format!("({})", vals.join(","))
+            })
+            .collect();
+        format!(
+            "INSERT INTO {} ({}) VALUES {}",
             self.table,
-            self.columns.join(","),
-            values.join(",")
-        );
-        db.query_drop(q)?;
-        Ok(ret)
+            insert_cols.join(","),
+            insert_rows.join(",")
+        )
+    }
+
+    // resolves references to previously generated values, and generates the rest
+    fn generate_values(&self, generated: &GeneratedValues) -> mysql::Result<Vec<mysql::Value>> {
+        let mut values: Vec<mysql::Value> = vec![];
+        for v in &self.values {
+            match (valuespec2value(v, generated), v) {
+                (Some(val), _) => values.push(val),
+                (None, ValueSpec::ColRef { table, col }) => {
+                    return Err(mysql::Error::IoError(io::Error::other(format!(
+                        "{} refers to {}.{}, which has not been generated yet",
+                        self.table, table, col
+                    ))))
+                }
+                (None, _) => {
+                    return Err(mysql::Error::IoError(io::Error::other(format!(
+                        "failed to generate a value for {}",
+                        self.table
+                    ))))
+                }
+            }
+        }
+        Ok(values)
     }
 }
 
-pub fn valuespec2value(vs: &ValueSpec) -> mysql::Value {
+impl ValueSpec {
+    // whether a fresh value is generated each time this spec is evaluated
+    pub fn is_random(&self) -> bool {
+        use ValueSpec::*;
+        matches!(
+            self,
+            RandNum { .. } | RandStr { .. } | RandEmail | RandPhone
+        )
+    }
+}
+
+// the value for a row; references are resolved against the values generated
+// so far for the same object, and are None if the value hasn't been generated
+pub fn valuespec2value(vs: &ValueSpec, generated: &GeneratedValues) -> Option<mysql::Value> {
     use mysql::Value::*;
     use ValueSpec::*;
-    match vs {
+    let value = match vs {
         ConstNum(n) => UInt(*n),
         ConstStr(s) => Bytes(s.clone().into_bytes()),
         RandNum { lb, ub } => {
@@ -265,7 +496,11 @@ pub fn valuespec2value(vs: &ValueSpec) -> mysql::Value {
         },
         ConstDate { year, month, day } => Date(*year, *month, *day, 0, 0, 0, 0),
         Null => NULL,
-    }
+        // never inserted; the database picks the value
+        AutoIncrement => NULL,
+        ColRef { table, col } => return generated.get(&(table.clone(), col.clone())).cloned(),
+    };
+    Some(value)
 }
 
 // tests
@@ -310,3 +545,71 @@ fn test_query_joined() {
         "SELECT * FROM target JOIN intermediate ON intermediate.int_fk = target.target_fk JOIN start ON start.start_fk = intermediate.int_fk WHERE start.col = 1"
     );
 }
+
+#[test]
+fn test_is_random() {
+    assert!(ValueSpec::RandNum { lb: 0, ub: 10 }.is_random());
+    assert!(ValueSpec::RandStr { len: 4 }.is_random());
+    assert!(ValueSpec::RandEmail.is_random());
+    assert!(ValueSpec::RandPhone.is_random());
+    assert!(!ValueSpec::ConstNum(1).is_random());
+    assert!(!ValueSpec::ConstStr("a".to_string()).is_random());
+    assert!(!ValueSpec::AutoIncrement.is_random());
+    assert!(!ValueSpec::ColRef {
+        table: "users".to_string(),
+        col: "id".to_string(),
+    }
+    .is_random());
+}
+
+#[test]
+fn test_generate_values() {
+    let ts = TableSpec {
+        table: "profiles".to_string(),
+        columns: vec!["user_id".to_string(), "bio".to_string()],
+        values: vec![
+            ValueSpec::ColRef {
+                table: "users".to_string(),
+                col: "id".to_string(),
+            },
+            ValueSpec::ConstStr("hi".to_string()),
+        ],
+    };
+    let mut generated = GeneratedValues::new();
+    match ts.generate_values(&generated) {
+        Err(mysql::Error::IoError(e)) => assert_eq!(
+            e.to_string(),
+            "profiles refers to users.id, which has not been generated yet"
+        ),
+        _ => panic!("expected an unresolved reference"),
+    }
+
+    generated.insert(
+        ("users".to_string(), "id".to_string()),
+        mysql::Value::UInt(7),
+    );
+    assert_eq!(
+        ts.generate_values(&generated).unwrap(),
+        vec![mysql::Value::UInt(7), mysql::Value::Bytes(b"hi".to_vec())]
+    );
+}
+
+#[test]
+fn test_insert_sql() {
+    let ts = TableSpec {
+        table: "users".to_string(),
+        columns: vec!["id".to_string(), "name".to_string(), "age".to_string()],
+        values: vec![
+            ValueSpec::AutoIncrement,
+            ValueSpec::ConstStr("alice".to_string()),
+            ValueSpec::ConstNum(30),
+        ],
+    };
+    let rows: Vec<Vec<mysql::Value>> = (0..2)
+        .map(|_| ts.generate_values(&GeneratedValues::new()).unwrap())
+        .collect();
+    assert_eq!(
+        ts.insert_sql(&rows),
+        "INSERT INTO users (name,age) VALUES ('alice',30),('alice',30)"
+    );
+}